@@ -0,0 +1,56 @@
+//! The allow_list checks every handler runs on every event.
+//!
+//! Run with `cargo bench --features testing`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use headjack::testing::TestBot;
+use headjack::BotConfig;
+use matrix_sdk::ruma::{room_id, user_id};
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+fn allow_list(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let config = BotConfig {
+        allow_list: vec![
+            "@alice:localhost".to_string(),
+            "@.*:example.org".to_string(),
+        ],
+        ..Default::default()
+    };
+    let bot = runtime.block_on(TestBot::new(config)).unwrap();
+    let room_id = room_id!("!room:localhost");
+    let override_id = room_id!("!override:localhost");
+    let sender = user_id!("@alice:localhost");
+    let (room, override_room) = runtime.block_on(async {
+        bot.inject_message(room_id, sender, "hi").await.unwrap();
+        bot.inject_message(override_id, sender, "hi").await.unwrap();
+        bot.inject_room_account_data(
+            override_id,
+            "dev.headjack.allow_list",
+            json!({ "allow_list": ["@alice:localhost", "@bob:localhost"] }),
+        )
+        .await
+        .unwrap();
+        let client = bot.client().unwrap();
+        (
+            client.get_room(room_id).unwrap(),
+            client.get_room(override_id).unwrap(),
+        )
+    });
+
+    c.bench_function("is_sender_allowed", |b| {
+        b.iter(|| bot.is_sender_allowed(sender))
+    });
+    c.bench_function("is_sender_allowed_in_room", |b| {
+        b.to_async(&runtime)
+            .iter(|| bot.is_sender_allowed_in_room(&room, sender))
+    });
+    c.bench_function("is_sender_allowed_in_room with an override", |b| {
+        b.to_async(&runtime)
+            .iter(|| bot.is_sender_allowed_in_room(&override_room, sender))
+    });
+}
+
+criterion_group!(benches, allow_list);
+criterion_main!(benches);
@@ -0,0 +1,93 @@
+//! Procedural macros for headjack.
+//!
+//! These are re-exported from the headjack crate, use them from there.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Expr, ExprLit, ItemFn, Lit, MetaNameValue, Token};
+
+/// Declare an async fn as a bot command
+///
+/// The function must have the same signature as a `register_text_command` callback,
+/// `async fn(Message, Room) -> Result<(), ()>`.
+/// It is registered automatically when the bot starts running.
+///
+/// Arguments:
+/// - `name`: The command string that triggers the command, required
+/// - `help`: Single line of help text
+/// - `args`: Argument format shown in the help
+#[proc_macro_attribute]
+pub fn command(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let func = parse_macro_input!(item as ItemFn);
+    let args = match Punctuated::<MetaNameValue, Token![,]>::parse_terminated.parse(attr) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    if func.sig.asyncness.is_none() {
+        return syn::Error::new_spanned(func.sig.fn_token, "commands must be async functions")
+            .to_compile_error()
+            .into();
+    }
+
+    let mut name = None;
+    let mut help = None;
+    let mut arg_format = None;
+    for arg in args {
+        let Expr::Lit(ExprLit {
+            lit: Lit::Str(value),
+            ..
+        }) = &arg.value
+        else {
+            return syn::Error::new_spanned(arg.value, "expected a string literal")
+                .to_compile_error()
+                .into();
+        };
+        if arg.path.is_ident("name") {
+            name = Some(value.value());
+        } else if arg.path.is_ident("help") {
+            help = Some(value.value());
+        } else if arg.path.is_ident("args") {
+            arg_format = Some(value.value());
+        } else {
+            return syn::Error::new_spanned(
+                arg.path,
+                "unknown argument, expected name, help or args",
+            )
+            .to_compile_error()
+            .into();
+        }
+    }
+    let Some(name) = name else {
+        return syn::Error::new(proc_macro2::Span::call_site(), "missing `name` argument")
+            .to_compile_error()
+            .into();
+    };
+    let help = option_tokens(help);
+    let arg_format = option_tokens(arg_format);
+    let ident = &func.sig.ident;
+
+    quote! {
+        #func
+
+        ::headjack::inventory::submit! {
+            ::headjack::CommandRegistration {
+                name: #name,
+                args: #arg_format,
+                help: #help,
+                handler: |message, room| ::std::boxed::Box::pin(#ident(message, room)),
+            }
+        }
+    }
+    .into()
+}
+
+/// Convert an optional string into the tokens for an `Option<&'static str>`
+fn option_tokens(value: Option<String>) -> proc_macro2::TokenStream {
+    match value {
+        Some(value) => quote! { ::std::option::Option::Some(#value) },
+        None => quote! { ::std::option::Option::None },
+    }
+}
@@ -0,0 +1,507 @@
+//! Ways to create a `BotConfig` without spelling out every field.
+
+use crate::{
+    check_command_prefix, BotConfig, ClientBuilderHook, HeadjackError, PrefixConfig, StoreConfig,
+    Strings,
+};
+use matrix_sdk::ClientBuilder;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Prefix of the environment variables read by `BotConfig::from_env`
+const ENV_PREFIX: &str = "HEADJACK_";
+
+impl BotConfig {
+    /// Start building a configuration
+    /// Only the homeserver and username are required, everything else keeps its default.
+    pub fn builder() -> BotConfigBuilder {
+        BotConfigBuilder::default()
+    }
+
+    /// Read the configuration from `HEADJACK_*` environment variables
+    /// See `BotConfigBuilder::env` for the variables that are read.
+    pub fn from_env() -> Result<BotConfig, HeadjackError> {
+        BotConfig::builder().env()?.build()
+    }
+
+    /// Read the configuration from a TOML file
+    /// The keys are the names of the `BotConfig` fields, with the login details in a `[login]`
+    /// table. To let environment variables and code override the file, use the builder:
+    /// `BotConfig::builder().toml_file(path)?.env()?.username("bot").build()`
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<BotConfig, HeadjackError> {
+        BotConfig::builder().toml_file(path)?.build()
+    }
+}
+
+/// Read an environment variable with the `HEADJACK_` prefix
+/// Returns None if it isn't set, and an error if it can't be parsed.
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, HeadjackError> {
+    let name = format!("{}{}", ENV_PREFIX, name);
+    let value = match env::var(&name) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            return Err(HeadjackError::Config(format!(
+                "{} is not valid unicode",
+                name
+            )))
+        }
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| HeadjackError::Config(format!("invalid value for {}: {}", name, value)))
+}
+
+/// Builder for a `BotConfig`, created with `BotConfig::builder`
+/// Each method sets the `BotConfig` field of the same name, see there for the details.
+#[derive(Debug, Clone, Default)]
+pub struct BotConfigBuilder {
+    /// The configuration being built
+    config: BotConfig,
+}
+
+impl BotConfigBuilder {
+    /// The homeserver URL or server name to connect to
+    pub fn homeserver(mut self, homeserver_url: impl Into<String>) -> Self {
+        self.config.login.homeserver_url = homeserver_url.into();
+        self
+    }
+
+    /// The username to login with
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.config.login.username = username.into();
+        self
+    }
+
+    /// The password to login with, otherwise it's asked for on the command line
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.config.login.password = Some(password.into());
+        self
+    }
+
+    /// The name of the bot, defaults to the username
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    /// Replaces the whole allow_list
+    pub fn allow_list<I, S>(mut self, allow_list: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.allow_list = allow_list.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Adds a single entry to the allow_list
+    pub fn allow(mut self, entry: impl Into<String>) -> Self {
+        self.config.allow_list.push(entry.into());
+        self
+    }
+
+    /// The ID of a room whose topic holds the allow_list
+    pub fn allow_list_room(mut self, room_id: impl Into<String>) -> Self {
+        self.config.allow_list_room = Some(room_id.into());
+        self
+    }
+
+    /// The directory the session and store are kept in
+    pub fn state_dir(mut self, state_dir: impl Into<String>) -> Self {
+        self.config.state_dir = Some(state_dir.into());
+        self
+    }
+
+    /// The prefix of the bot's commands, e.g. "!"
+    pub fn command_prefix(mut self, command_prefix: impl Into<String>) -> Self {
+        self.config.command_prefix = Some(command_prefix.into());
+        self
+    }
+
+    /// Also accept commands addressed by mentioning the bot
+    pub fn mention_commands(mut self, enabled: bool) -> Self {
+        self.config.mention_commands = Some(enabled);
+        self
+    }
+
+    /// How strictly the command_prefix is matched
+    pub fn prefix_config(mut self, prefix_config: PrefixConfig) -> Self {
+        self.config.prefix_config = Some(prefix_config);
+        self
+    }
+
+    /// Refuse to join rooms with more members than this
+    pub fn room_size_limit(mut self, room_size_limit: usize) -> Self {
+        self.config.room_size_limit = Some(room_size_limit);
+        self
+    }
+
+    /// Only call the text handler for mentions in rooms with more members than this
+    pub fn mention_only_room_size(mut self, mention_only_room_size: u64) -> Self {
+        self.config.mention_only_room_size = Some(mention_only_room_size);
+        self
+    }
+
+    /// Register the builtin ping command
+    pub fn ping_command(mut self, enabled: bool) -> Self {
+        self.config.ping_command = Some(enabled);
+        self
+    }
+
+    /// Register the builtin rooms command
+    pub fn rooms_command(mut self, enabled: bool) -> Self {
+        self.config.rooms_command = Some(enabled);
+        self
+    }
+
+    /// Register the builtin whoami command
+    pub fn whoami_command(mut self, enabled: bool) -> Self {
+        self.config.whoami_command = Some(enabled);
+        self
+    }
+
+    /// Register the builtin ignore, unignore and ignored commands
+    pub fn ignore_commands(mut self, enabled: bool) -> Self {
+        self.config.ignore_commands = Some(enabled);
+        self
+    }
+
+    /// Register the builtin purge command
+    pub fn purge_command(mut self, enabled: bool) -> Self {
+        self.config.purge_command = Some(enabled);
+        self
+    }
+
+    /// The power level needed to use the admin commands
+    pub fn admin_power_level(mut self, power_level: i64) -> Self {
+        self.config.admin_power_level = Some(power_level);
+        self
+    }
+
+    /// The store backend of the matrix client
+    pub fn store(mut self, store: StoreConfig) -> Self {
+        self.config.store = Some(store);
+        self
+    }
+
+    /// The minimum interval between writes of the sync token to disk
+    pub fn sync_token_persist_interval(mut self, interval: Duration) -> Self {
+        self.config.sync_token_persist_interval = Some(interval);
+        self
+    }
+
+    /// Register the builtin help command
+    pub fn builtin_help(mut self, enabled: bool) -> Self {
+        self.config.builtin_help = Some(enabled);
+        self
+    }
+
+    /// The name of the builtin help command
+    pub fn help_command(mut self, help_command: impl Into<String>) -> Self {
+        self.config.help_command = Some(help_command.into());
+        self
+    }
+
+    /// Only log what would be sent, without sending anything
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.config.read_only = Some(enabled);
+        self
+    }
+
+    /// Include the full message bodies in the logs
+    pub fn log_message_bodies(mut self, enabled: bool) -> Self {
+        self.config.log_message_bodies = Some(enabled);
+        self
+    }
+
+    /// Refuse to send messages to unencrypted rooms
+    pub fn require_encryption(mut self, enabled: bool) -> Self {
+        self.config.require_encryption = Some(enabled);
+        self
+    }
+
+    /// Deliver the messages to each room in the order they were sent
+    pub fn ordered_sends(mut self, enabled: bool) -> Self {
+        self.config.ordered_sends = Some(enabled);
+        self
+    }
+
+    /// The user facing strings of the builtin commands
+    pub fn strings(mut self, strings: Strings) -> Self {
+        self.config.strings = Some(strings);
+        self
+    }
+
+    /// Only respond to commands addressed to this bot by name
+    pub fn multi_bot(mut self, enabled: bool) -> Self {
+        self.config.multi_bot = Some(enabled);
+        self
+    }
+
+    /// How often a request is retried when it fails or is rate limited
+    pub fn max_retries(mut self, max_retries: u64) -> Self {
+        self.config.max_retries = Some(max_retries);
+        self
+    }
+
+    /// How long a command may run before it's cancelled
+    pub fn command_timeout(mut self, command_timeout: Duration) -> Self {
+        self.config.command_timeout = Some(command_timeout);
+        self
+    }
+
+    /// Only run the first command a user sends within this long
+    pub fn command_burst_window(mut self, command_burst_window: Duration) -> Self {
+        self.config.command_burst_window = Some(command_burst_window);
+        self
+    }
+
+    /// The maximum number of commands that run at the same time
+    pub fn max_concurrent_commands(mut self, max_concurrent_commands: usize) -> Self {
+        self.config.max_concurrent_commands = Some(max_concurrent_commands);
+        self
+    }
+
+    /// Only handle messages in rooms tagged with this namespace and tag
+    pub fn required_room_tag(
+        mut self,
+        namespace: impl Into<String>,
+        tag: impl Into<String>,
+    ) -> Self {
+        self.config.required_room_tag = Some((namespace.into(), tag.into()));
+        self
+    }
+
+    /// Also pass notices to the commands and the text handler
+    pub fn handle_notices(mut self, enabled: bool) -> Self {
+        self.config.handle_notices = Some(enabled);
+        self
+    }
+
+    /// Record the conversation in each room, keeping this many messages
+    pub fn max_session_turns(mut self, max_session_turns: usize) -> Self {
+        self.config.max_session_turns = Some(max_session_turns);
+        self
+    }
+
+    /// The User-Agent sent to the homeserver
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// The proxy to connect to the homeserver through
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.config.proxy = Some(proxy.into());
+        self
+    }
+
+    /// INSECURE: see `BotConfig::accept_invalid_certs`
+    pub fn accept_invalid_certs(mut self, enabled: bool) -> Self {
+        self.config.accept_invalid_certs = Some(enabled);
+        self
+    }
+
+    /// Customize the client builder right before the client is built
+    pub fn client_builder_hook(
+        mut self,
+        hook: impl Fn(ClientBuilder) -> ClientBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.config.client_builder_hook = Some(ClientBuilderHook::new(hook));
+        self
+    }
+
+    /// Leave presence, typing notifications and read receipts out of syncs
+    pub fn ignore_ephemeral(mut self, enabled: bool) -> Self {
+        self.config.ignore_ephemeral = Some(enabled);
+        self
+    }
+
+    /// Handle the messages that arrived while the bot was offline
+    pub fn process_backfill(mut self, enabled: bool) -> Self {
+        self.config.process_backfill = Some(enabled);
+        self
+    }
+
+    /// Sync with sliding sync instead of `/sync`, needs the `sliding-sync` feature
+    pub fn sliding_sync(mut self, enabled: bool) -> Self {
+        self.config.sliding_sync = Some(enabled);
+        self
+    }
+
+    /// Set the URL of the sliding sync proxy
+    pub fn sliding_sync_proxy(mut self, url: impl Into<String>) -> Self {
+        self.config.sliding_sync_proxy = Some(url.into());
+        self
+    }
+
+    /// Collect the messages a user sends within this long into one
+    pub fn text_debounce(mut self, text_debounce: Duration) -> Self {
+        self.config.text_debounce = Some(text_debounce);
+        self
+    }
+
+    /// Read the fields from a TOML file, replacing the values set so far
+    /// Call this first, so that `env` and the other methods can override the file.
+    pub fn toml_file(mut self, path: impl AsRef<Path>) -> Result<Self, HeadjackError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        self.config = toml::from_str(&contents).map_err(|e| {
+            HeadjackError::Config(format!("invalid config file {}: {}", path.display(), e))
+        })?;
+        Ok(self)
+    }
+
+    /// Set the fields from environment variables, overriding the values set so far
+    /// The login is read from `HEADJACK_HOMESERVER`, `HEADJACK_USERNAME` and `HEADJACK_PASSWORD`.
+    /// The other fields are read from `HEADJACK_` and the field name in upper case, e.g.
+    /// `HEADJACK_ROOM_SIZE_LIMIT`. `HEADJACK_ALLOW_LIST` is comma separated.
+    /// The `store`, `strings`, `required_room_tag` and durations can't be set from the
+    /// environment.
+    /// Unset variables leave the field unchanged. Booleans are "true" or "false".
+    pub fn env(mut self) -> Result<Self, HeadjackError> {
+        let config = &mut self.config;
+        if let Some(homeserver_url) = env_var("HOMESERVER")? {
+            config.login.homeserver_url = homeserver_url;
+        }
+        if let Some(username) = env_var("USERNAME")? {
+            config.login.username = username;
+        }
+        if let Some(password) = env_var("PASSWORD")? {
+            config.login.password = Some(password);
+        }
+        if let Some(allow_list) = env_var::<String>("ALLOW_LIST")? {
+            config.allow_list = allow_list
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        config.allow_list_room = env_var("ALLOW_LIST_ROOM")?.or(config.allow_list_room.take());
+        config.name = env_var("NAME")?.or(config.name.take());
+        config.state_dir = env_var("STATE_DIR")?.or(config.state_dir.take());
+        config.command_prefix = env_var("COMMAND_PREFIX")?.or(config.command_prefix.take());
+        config.mention_commands = env_var("MENTION_COMMANDS")?.or(config.mention_commands);
+        config.room_size_limit = env_var("ROOM_SIZE_LIMIT")?.or(config.room_size_limit);
+        config.mention_only_room_size =
+            env_var("MENTION_ONLY_ROOM_SIZE")?.or(config.mention_only_room_size);
+        config.ping_command = env_var("PING_COMMAND")?.or(config.ping_command);
+        config.rooms_command = env_var("ROOMS_COMMAND")?.or(config.rooms_command);
+        config.whoami_command = env_var("WHOAMI_COMMAND")?.or(config.whoami_command);
+        config.ignore_commands = env_var("IGNORE_COMMANDS")?.or(config.ignore_commands);
+        config.purge_command = env_var("PURGE_COMMAND")?.or(config.purge_command);
+        config.admin_power_level = env_var("ADMIN_POWER_LEVEL")?.or(config.admin_power_level);
+        config.builtin_help = env_var("BUILTIN_HELP")?.or(config.builtin_help);
+        config.help_command = env_var("HELP_COMMAND")?.or(config.help_command.take());
+        config.read_only = env_var("READ_ONLY")?.or(config.read_only);
+        config.log_message_bodies = env_var("LOG_MESSAGE_BODIES")?.or(config.log_message_bodies);
+        config.require_encryption = env_var("REQUIRE_ENCRYPTION")?.or(config.require_encryption);
+        config.ordered_sends = env_var("ORDERED_SENDS")?.or(config.ordered_sends);
+        config.multi_bot = env_var("MULTI_BOT")?.or(config.multi_bot);
+        config.max_retries = env_var("MAX_RETRIES")?.or(config.max_retries);
+        config.max_concurrent_commands =
+            env_var("MAX_CONCURRENT_COMMANDS")?.or(config.max_concurrent_commands);
+        config.handle_notices = env_var("HANDLE_NOTICES")?.or(config.handle_notices);
+        config.max_session_turns = env_var("MAX_SESSION_TURNS")?.or(config.max_session_turns);
+        config.user_agent = env_var("USER_AGENT")?.or(config.user_agent.take());
+        config.proxy = env_var("PROXY")?.or(config.proxy.take());
+        config.accept_invalid_certs =
+            env_var("ACCEPT_INVALID_CERTS")?.or(config.accept_invalid_certs);
+        config.ignore_ephemeral = env_var("IGNORE_EPHEMERAL")?.or(config.ignore_ephemeral);
+        config.process_backfill = env_var("PROCESS_BACKFILL")?.or(config.process_backfill);
+        config.sliding_sync = env_var("SLIDING_SYNC")?.or(config.sliding_sync);
+        config.sliding_sync_proxy =
+            env_var("SLIDING_SYNC_PROXY")?.or(config.sliding_sync_proxy.take());
+        Ok(self)
+    }
+
+    /// Finish the configuration
+    /// Fails if the homeserver or username wasn't set, or the command_prefix is empty.
+    pub fn build(self) -> Result<BotConfig, HeadjackError> {
+        if self.config.login.homeserver_url.is_empty() {
+            return Err(HeadjackError::Config("no homeserver set".to_string()));
+        }
+        if self.config.login.username.is_empty() {
+            return Err(HeadjackError::Config("no username set".to_string()));
+        }
+        check_command_prefix(self.config.command_prefix.as_deref())?;
+        Ok(self.config)
+    }
+}
+
+/// Read an optional duration from a config file
+/// Accepts seconds, e.g. `30` or `0.5`, or a string with units, e.g. `"500ms"` or `"1m 30s"`.
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Seconds(f64),
+        Text(String),
+    }
+
+    match Option::<RawDuration>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawDuration::Seconds(secs)) => Duration::try_from_secs_f64(secs)
+            .map(Some)
+            .map_err(D::Error::custom),
+        Some(RawDuration::Text(text)) => humantime::parse_duration(&text)
+            .map(Some)
+            .map_err(D::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_rejects_an_empty_command_prefix() {
+        for prefix in ["", "  "] {
+            let result = BotConfig::builder()
+                .homeserver("https://example.org")
+                .username("bot")
+                .command_prefix(prefix)
+                .build();
+            assert!(
+                matches!(result, Err(HeadjackError::Config(_))),
+                "{:?}: {:?}",
+                prefix,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn build_accepts_a_command_prefix() {
+        let config = BotConfig::builder()
+            .homeserver("https://example.org")
+            .username("bot")
+            .command_prefix("!")
+            .build()
+            .unwrap();
+        assert_eq!(config.command_prefix.as_deref(), Some("!"));
+    }
+
+    #[test]
+    fn durations_are_read_as_seconds_or_with_units() {
+        for (value, expected) in [
+            ("30", Duration::from_secs(30)),
+            ("0.5", Duration::from_millis(500)),
+            ("\"1m 30s\"", Duration::from_secs(90)),
+        ] {
+            let config: BotConfig = toml::from_str(&format!("command_timeout = {value}")).unwrap();
+            assert_eq!(config.command_timeout, Some(expected), "{value}");
+        }
+        assert!(toml::from_str::<BotConfig>("command_timeout = \"soon\"").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Managing the allow_list from a Matrix room, see `allow_list_room`.
+//!
+//! The topic of the control room holds the allow_list, one regex per line, so access can be
+//! changed from any client by editing the topic. Only users with the power level to change the
+//! topic can change the allow_list, so keep the control room to the bot's operators.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::events::room::topic::SyncRoomTopicEvent;
+use matrix_sdk::ruma::RoomId;
+use tracing::{error, info};
+
+/// Parse the allow_list from the topic of the control room
+/// Each non-empty line is an entry. An empty list is rejected, as it would lock everyone out.
+fn parse_topic(topic: &str) -> Result<Vec<String>, String> {
+    let allow_list: Vec<String> = topic
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    if allow_list.is_empty() {
+        return Err("the topic has no entries".to_string());
+    }
+    Ok(allow_list)
+}
+
+impl Bot {
+    /// Read the allow_list from the topic of the `allow_list_room`, and follow its changes
+    pub(crate) fn watch_allow_list_room(&self, room_id: &str) -> Result<(), HeadjackError> {
+        let room_id = RoomId::parse(room_id).map_err(|e| {
+            HeadjackError::Config(format!("invalid allow_list_room {}: {}", room_id, e))
+        })?;
+        let client = self.client()?;
+        if let Some(topic) = client.get_room(&room_id).and_then(|room| room.topic()) {
+            self.apply_allow_list_topic(&topic);
+        }
+        let bot = self.clone();
+        client.add_room_event_handler(&room_id, move |event: SyncRoomTopicEvent| async move {
+            if let Some(event) = event.as_original() {
+                info!("allow_list changed by {}", event.sender);
+                bot.apply_allow_list_topic(&event.content.topic);
+            }
+        });
+        Ok(())
+    }
+
+    /// Replace the allow_list with the one in the topic, keeping the current one if it's invalid
+    fn apply_allow_list_topic(&self, topic: &str) {
+        let result = parse_topic(topic).and_then(|allow_list| {
+            self.replace_allow_list(allow_list)
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            error!(
+                "Invalid allow_list in the allow_list_room, keeping the old one: {}",
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Per room conversation history, persisted to disk.
+//!
+//! Enabled with `max_session_turns`. The text handler records the messages it handles and the
+//! messages the bot sends, so that chat bots can give e.g. an LLM the context of the conversation.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, RoomId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// A single message in a conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatTurn {
+    /// The user that sent the message
+    pub sender: OwnedUserId,
+    /// The text of the message
+    pub body: String,
+    /// When the message was received by the homeserver
+    pub ts: MilliSecondsSinceUnixEpoch,
+}
+
+/// The conversations of all rooms, cached in memory and written to a JSON file per room
+/// The files are read and written without holding the state lock, so that recording doesn't
+/// block the other handlers.
+#[derive(Debug, Default)]
+pub(crate) struct SessionStore {
+    /// The conversations that have been read or written, by room
+    sessions: HashMap<OwnedRoomId, Vec<ChatTurn>>,
+    /// Held while the file of a room is written, so that the writes don't interleave
+    files: HashMap<OwnedRoomId, Arc<Mutex<()>>>,
+}
+
+impl SessionStore {
+    /// The lock on the file of the room
+    fn file_lock(&mut self, room_id: &RoomId) -> Arc<Mutex<()>> {
+        self.files.entry(room_id.to_owned()).or_default().clone()
+    }
+}
+
+/// The file the conversation of a room is stored in
+/// Room IDs contain characters that aren't allowed in file names everywhere, so they're replaced.
+fn session_file(dir: &Path, room_id: &RoomId) -> PathBuf {
+    let name: String = room_id
+        .as_str()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{}.json", name))
+}
+
+impl Bot {
+    /// Get the recorded conversation of a room, oldest message first
+    /// Empty unless `max_session_turns` is set.
+    pub async fn session(&self, room_id: &RoomId) -> Result<Vec<ChatTurn>, HeadjackError> {
+        self.load_session(room_id).await?;
+        let state = self.state.lock().await;
+        Ok(state
+            .sessions
+            .sessions
+            .get(room_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Forget the recorded conversation of a room
+    pub async fn session_clear(&self, room_id: &RoomId) -> Result<(), HeadjackError> {
+        let file = session_file(&self.sessions_dir(), room_id);
+        let file_lock = {
+            let mut state = self.state.lock().await;
+            state.sessions.sessions.remove(room_id);
+            state.sessions.file_lock(room_id)
+        };
+        let _writing = file_lock.lock().await;
+        if file.exists() {
+            fs::remove_file(file).await?;
+        }
+        Ok(())
+    }
+
+    /// Read the conversation of the room from disk, if it isn't cached yet
+    async fn load_session(&self, room_id: &RoomId) -> Result<(), HeadjackError> {
+        if self
+            .state
+            .lock()
+            .await
+            .sessions
+            .sessions
+            .contains_key(room_id)
+        {
+            return Ok(());
+        }
+        let file = session_file(&self.sessions_dir(), room_id);
+        let turns = if file.exists() {
+            serde_json::from_str(&fs::read_to_string(&file).await?)?
+        } else {
+            Vec::new()
+        };
+        // Another handler may have loaded it in the meantime, and already added to it
+        self.state
+            .lock()
+            .await
+            .sessions
+            .sessions
+            .entry(room_id.to_owned())
+            .or_insert(turns);
+        Ok(())
+    }
+
+    /// Add a message to the conversation of a room, if sessions are enabled
+    /// Only the last `max_session_turns` messages are kept.
+    pub(crate) async fn record_turn(
+        &self,
+        room_id: &RoomId,
+        turn: ChatTurn,
+    ) -> Result<(), HeadjackError> {
+        let Some(max_turns) = self.config().max_session_turns else {
+            return Ok(());
+        };
+        self.load_session(room_id).await?;
+        let file_lock = {
+            let mut state = self.state.lock().await;
+            let turns = state
+                .sessions
+                .sessions
+                .entry(room_id.to_owned())
+                .or_default();
+            turns.push(turn);
+            if turns.len() > max_turns {
+                turns.drain(..turns.len() - max_turns);
+            }
+            state.sessions.file_lock(room_id)
+        };
+
+        // The latest turns are written, so a write that waited here includes the earlier turns
+        let _writing = file_lock.lock().await;
+        let json = {
+            let state = self.state.lock().await;
+            let Some(turns) = state.sessions.sessions.get(room_id) else {
+                // Cleared in the meantime
+                return Ok(());
+            };
+            serde_json::to_string(turns)?
+        };
+        let dir = self.sessions_dir();
+        fs::create_dir_all(&dir).await?;
+        fs::write(session_file(&dir, room_id), json).await?;
+        Ok(())
+    }
+
+    /// The directory the conversations are stored in
+    fn sessions_dir(&self) -> PathBuf {
+        self.state_dir().join("sessions")
+    }
+}
@@ -0,0 +1,165 @@
+//! The error type returned by the public API.
+
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::{ClientBuildError, HttpError};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Errors returned by headjack
+#[derive(Debug, Error)]
+pub enum HeadjackError {
+    /// The bot isn't logged in yet, call `Bot::login` first
+    #[error("the bot is not logged in")]
+    NotLoggedIn,
+
+    /// The homeserver rejected the login credentials
+    #[error("invalid login credentials: {0}")]
+    BadCredentials(#[source] Box<matrix_sdk::Error>),
+
+    /// The homeserver is rate limiting the bot, and the retries set by `max_retries` ran out
+    #[error("rate limited by the homeserver: {source}")]
+    RateLimited {
+        /// How long the homeserver asked to wait before trying again
+        retry_after: Option<Duration>,
+        /// The error returned for the request
+        source: Box<HttpError>,
+    },
+
+    /// The homeserver invalidated the access token, e.g. because an admin logged the bot out
+    /// After a soft logout the device and its encryption keys are kept, and with a password in
+    /// the config the session file is already renewed, so restarting the bot resumes. Otherwise
+    /// delete the session file and the store to log in as a new device.
+    #[error("the access token was invalidated by the homeserver (soft logout: {soft_logout})")]
+    TokenInvalidated {
+        /// Whether the device can log in again and keep its encryption keys
+        soft_logout: bool,
+    },
+
+    /// The homeserver couldn't be reached
+    #[error("could not reach the homeserver: {0}")]
+    Network(#[source] Box<HttpError>),
+
+    /// The session file couldn't be parsed
+    /// Deleting it will make the bot log in again.
+    #[error("the session file is corrupt: {0}")]
+    SessionCorrupt(#[source] serde_json::Error),
+
+    /// The configuration is invalid or incomplete
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    /// An allow_list entry isn't a valid regular expression
+    #[error("invalid allow_list entry: {0}")]
+    InvalidRegex(#[from] regex::Error),
+
+    /// Refused to send to an unencrypted room, see `require_encryption`
+    #[error("refusing to send to unencrypted room {0}")]
+    Unencrypted(OwnedRoomId),
+
+    /// The bot's power level is too low to change the room state, e.g. its topic
+    #[error("missing the power level to send {event_type} in room {room_id}")]
+    MissingPermission {
+        /// The room the state was changed in
+        room_id: OwnedRoomId,
+        /// The type of the state event
+        event_type: StateEventType,
+    },
+
+    /// The homeserver refused to add another tag to the room
+    /// Tags are meant for a few markers per room, store larger state in account data instead.
+    #[error("too many tags on room {room_id}: {source}")]
+    TagLimitExceeded {
+        /// The room the tag was added to
+        room_id: OwnedRoomId,
+        /// The error returned by the homeserver
+        source: Box<HttpError>,
+    },
+
+    /// A poll couldn't be sent, or the event isn't a poll
+    #[error("invalid poll: {0}")]
+    Poll(String),
+
+    /// The passphrase of the store couldn't be read or saved
+    #[error("passphrase unavailable: {0}")]
+    Passphrase(String),
+
+    /// The client couldn't be built
+    #[error(transparent)]
+    ClientBuild(Box<ClientBuildError>),
+
+    /// Any other error from the Matrix SDK
+    #[error(transparent)]
+    Matrix(Box<matrix_sdk::Error>),
+
+    /// Failed to (de)serialize data
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// Failed to read or write a file
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Failed to access the OS keyring
+    #[cfg(feature = "keyring")]
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+}
+
+impl HeadjackError {
+    /// Create the error for a failed login
+    /// Only an explicit rejection by the homeserver counts as bad credentials.
+    pub(crate) fn login(error: matrix_sdk::Error) -> Self {
+        match error.client_api_error_kind() {
+            Some(ErrorKind::Forbidden) | Some(ErrorKind::UserDeactivated) => {
+                HeadjackError::BadCredentials(Box::new(error))
+            }
+            _ => error.into(),
+        }
+    }
+
+    /// Create the error for a tag that couldn't be added
+    /// Homeservers reject new tags as too large once the room has too many.
+    pub(crate) fn tag(room_id: &RoomId, error: HttpError) -> Self {
+        match error.client_api_error_kind() {
+            Some(ErrorKind::TooLarge) | Some(ErrorKind::ResourceLimitExceeded { .. }) => {
+                HeadjackError::TagLimitExceeded {
+                    room_id: room_id.to_owned(),
+                    source: Box::new(error),
+                }
+            }
+            _ => error.into(),
+        }
+    }
+}
+
+impl From<matrix_sdk::Error> for HeadjackError {
+    fn from(error: matrix_sdk::Error) -> Self {
+        match error {
+            matrix_sdk::Error::Http(error) => error.into(),
+            error => HeadjackError::Matrix(Box::new(error)),
+        }
+    }
+}
+
+impl From<HttpError> for HeadjackError {
+    fn from(error: HttpError) -> Self {
+        if let Some(ErrorKind::LimitExceeded { retry_after_ms }) = error.client_api_error_kind() {
+            return HeadjackError::RateLimited {
+                retry_after: *retry_after_ms,
+                source: Box::new(error),
+            };
+        }
+        match error {
+            HttpError::Reqwest(_) => HeadjackError::Network(Box::new(error)),
+            error => HeadjackError::Matrix(Box::new(matrix_sdk::Error::Http(error))),
+        }
+    }
+}
+
+impl From<ClientBuildError> for HeadjackError {
+    fn from(error: ClientBuildError) -> Self {
+        HeadjackError::ClientBuild(Box::new(error))
+    }
+}
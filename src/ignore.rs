@@ -0,0 +1,175 @@
+//! Users the bot ignores, managed at runtime.
+//!
+//! The ignored users are stored in the bot's account data, so they persist across restarts and
+//! apply in every room, on top of the allow_list.
+
+use crate::{Bot, HeadjackError, Message};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// The account data type used to store the ignored users
+const IGNORED_USERS_TYPE: &str = "dev.headjack.ignored_users";
+
+/// Contents of the ignored users account data
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IgnoredUsers {
+    /// The users the bot doesn't respond to
+    #[serde(default)]
+    users: Vec<OwnedUserId>,
+}
+
+/// Read the ignored users from the account data
+async fn get_ignored_users(client: &Client) -> Vec<OwnedUserId> {
+    let raw = match client
+        .account()
+        .account_data_raw(IGNORED_USERS_TYPE.into())
+        .await
+    {
+        Ok(Some(raw)) => raw,
+        Ok(None) => return Vec::new(),
+        Err(e) => {
+            error!("Error reading the ignored users: {:?}", e);
+            return Vec::new();
+        }
+    };
+    match raw.deserialize_as::<IgnoredUsers>() {
+        Ok(ignored) => ignored.users,
+        Err(e) => {
+            error!("Invalid ignored users: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Check if the sender is on the ignore list
+pub(crate) async fn is_ignored(client: &Client, sender: &str) -> bool {
+    get_ignored_users(client)
+        .await
+        .iter()
+        .any(|user| user.as_str() == sender)
+}
+
+impl Bot {
+    /// Get the users the bot ignores
+    pub async fn ignored_users(&self) -> Result<Vec<OwnedUserId>, HeadjackError> {
+        Ok(get_ignored_users(self.client()?).await)
+    }
+
+    /// Ignore all messages, commands and invites from a user, in every room
+    /// Does nothing if the user is already ignored.
+    pub async fn ignore_user(&self, user_id: &UserId) -> Result<(), HeadjackError> {
+        let mut users = self.ignored_users().await?;
+        if users.iter().any(|user| user == user_id) {
+            return Ok(());
+        }
+        users.push(user_id.to_owned());
+        self.set_ignored_users(users).await
+    }
+
+    /// Stop ignoring a user
+    /// Does nothing if the user isn't ignored.
+    pub async fn unignore_user(&self, user_id: &UserId) -> Result<(), HeadjackError> {
+        let mut users = self.ignored_users().await?;
+        let count = users.len();
+        users.retain(|user| user != user_id);
+        if users.len() == count {
+            return Ok(());
+        }
+        self.set_ignored_users(users).await
+    }
+
+    /// Replace the ignored users in the account data
+    async fn set_ignored_users(&self, users: Vec<OwnedUserId>) -> Result<(), HeadjackError> {
+        let content = serde_json::value::to_raw_value(&IgnoredUsers { users })?;
+        self.client()?
+            .account()
+            .set_account_data_raw(IGNORED_USERS_TYPE.into(), Raw::from_json(content))
+            .await?;
+        Ok(())
+    }
+
+    /// Create the ignore, unignore and ignored commands
+    /// Only usable by room admins.
+    pub(crate) async fn register_ignore_commands(&self) -> Result<(), HeadjackError> {
+        let strings = self.strings();
+        let bot = self.clone();
+        self.register_text_command(
+            "ignore",
+            Some("<user>".to_string()),
+            Some(strings.ignore_description),
+            |message, room| async move {
+                bot.change_ignored(&room, &message, true).await;
+                Ok(())
+            },
+        )
+        .await?;
+        let bot = self.clone();
+        self.register_text_command(
+            "unignore",
+            Some("<user>".to_string()),
+            Some(strings.unignore_description),
+            |message, room| async move {
+                bot.change_ignored(&room, &message, false).await;
+                Ok(())
+            },
+        )
+        .await?;
+        let bot = self.clone();
+        self.register_text_command(
+            "ignored",
+            None,
+            Some(strings.ignored_description),
+            |message, room| async move {
+                if !bot.is_admin(&room, &message.sender).await {
+                    bot.reply_text(&room, &message, bot.strings().not_allowed)
+                        .await;
+                    return Ok(());
+                }
+                let users = bot.ignored_users().await.map_err(|_| ())?;
+                let mut response = bot.strings().ignored_header;
+                for user in users {
+                    response.push_str(&format!("\n- `{}`", user));
+                }
+                bot.send_in_thread(
+                    &room,
+                    message.thread_root(),
+                    RoomMessageEventContent::text_markdown(response),
+                )
+                .await
+                .map_err(|_| ())?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Handle the ignore and unignore commands
+    async fn change_ignored(&self, room: &Room, message: &Message, ignore: bool) {
+        let strings = self.strings();
+        if !self.is_admin(room, &message.sender).await {
+            self.reply_text(room, message, strings.not_allowed).await;
+            return;
+        }
+        let Ok(user_id) = <&UserId>::try_from(message.args.as_str()) else {
+            let response = strings.invalid_user.replace("{user}", &message.args);
+            self.reply_text(room, message, response).await;
+            return;
+        };
+        let (result, response) = if ignore {
+            (self.ignore_user(user_id).await, strings.ignore_done)
+        } else {
+            (self.unignore_user(user_id).await, strings.unignore_done)
+        };
+        match result {
+            Ok(()) => {
+                let response = response.replace("{user}", user_id.as_str());
+                self.reply_text(room, message, response).await;
+            }
+            Err(e) => error!("Error changing the ignored users: {:?}", e),
+        }
+    }
+}
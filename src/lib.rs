@@ -1,37 +1,95 @@
-use lazy_static::lazy_static;
-use matrix_sdk::ruma::events::room::member::StrippedRoomMemberEvent;
+mod config;
+mod control_room;
+mod conversation;
+mod error;
+mod ignore;
+#[cfg(feature = "macros")]
+mod macros;
+mod manager;
+mod message;
+mod paginate;
+mod poll;
+mod replay;
+#[cfg(feature = "sliding-sync")]
+mod sliding_sync;
+mod stream;
+mod strings;
+mod tags;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod verification;
+
+pub use config::BotConfigBuilder;
+pub use conversation::ChatTurn;
+use conversation::SessionStore;
+pub use error::HeadjackError;
+use ignore::is_ignored;
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub use inventory;
+#[cfg(feature = "macros")]
+pub use macros::{command, CommandRegistration};
+pub use manager::BotManager;
+use message::{escape_markdown, strip_reply_fallback};
+pub use message::{render_template, HistoryEvent, Message, TypingGuard};
+pub use paginate::{PaginatedMessage, NEXT_PAGE, PREVIOUS_PAGE};
+pub use poll::PollOption;
+pub use stream::ResponseStream;
+pub use strings::Strings;
+use tags::tag_name;
+pub use tags::{add_tag, get_tags, MultiTags, Tags};
+
+use futures_util::future::join_all;
+use matrix_sdk::reqwest::Url;
+use matrix_sdk::ruma::api::client::config::set_room_account_data;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::session::get_login_types::v3::LoginType;
+use matrix_sdk::ruma::api::client::session::login::{
+    self,
+    v3::{LoginInfo, Password},
+};
+use matrix_sdk::ruma::api::client::uiaa::UserIdentifier;
+use matrix_sdk::ruma::events::reaction::OriginalSyncReactionEvent;
+use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
+use matrix_sdk::ruma::events::room::member::{
+    MembershipState, OriginalSyncRoomMemberEvent, StrippedRoomMemberEvent,
+};
 use matrix_sdk::ruma::events::room::message::MessageType;
 use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::events::room::message::Relation;
 use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::events::AnySyncMessageLikeEvent;
-use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
+use matrix_sdk::ruma::events::{
+    AnyRoomAccountDataEvent, AnySyncMessageLikeEvent, AnySyncTimelineEvent, OriginalSyncStateEvent,
+    StateEventType, StaticEventContent, StaticStateEventContent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, ServerName, UserId};
 use matrix_sdk::RoomMemberships;
 use matrix_sdk::RoomState;
 use matrix_sdk::{
-    config::SyncSettings, matrix_auth::MatrixSession, ruma::api::client::filter::FilterDefinition,
-    Client, Error, LoopCtrl, Room,
+    config::{RequestConfig, SyncSettings},
+    event_handler::RawEvent,
+    matrix_auth::{MatrixSession, MatrixSessionTokens},
+    ruma::api::client::filter::{Filter, FilterDefinition, RoomEventFilter},
+    sync::SyncResponse,
+    Client, ClientBuilder, Error, HttpError, LoopCtrl, Room,
 };
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use regex::{Regex, RegexSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::time::sleep;
-use tracing::{error, info, warn};
-
-// The structure of the matrix rust sdk requires that any state that you need access to in the callbacks
-// is 'static.
-// This is a bit of a pain, so we need to use a global state to store the actual bot state for ease of use.
-
-lazy_static! {
-    ///  Stores the global state for all bots.
-    /// The key is the user ID of the bot
-    static ref GLOBAL_STATE: Mutex<HashMap<String, Mutex<State>>> = Mutex::new(HashMap::new());
-}
+use tracing::{error, info, info_span, trace, warn, Instrument};
 
 /// The data needed to re-build a client.
 #[derive(Debug, Serialize, Deserialize)]
@@ -43,9 +101,51 @@ struct ClientSession {
     db_path: PathBuf,
 
     /// The passphrase of the database.
-    passphrase: String,
+    /// Only set if the passphrase is stored in the session file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    passphrase: Option<String>,
+
+    /// Where the passphrase of the database is stored.
+    #[serde(default)]
+    passphrase_store: PassphraseStore,
+}
+
+/// The `command_prefix` that makes the bot respond to commands that start with a mention of it
+pub const MENTION_PREFIX: &str = "@mention";
+
+/// The number of consecutive failures to persist the sync token before `run()` gives up
+const MAX_PERSIST_FAILURES: u32 = 10;
+
+/// How often the progress of a slow initial sync is logged
+const INITIAL_SYNC_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many commands are listed on each page of the help
+const HELP_PAGE_SIZE: usize = 20;
+
+/// How long an autojoin waits for the joined room to come down a sync
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often an autojoin checks whether the joined room has come down a sync
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The room account data type used to store a per-room allow_list override
+const ROOM_ALLOW_LIST_TYPE: &str = "dev.headjack.allow_list";
+
+/// Contents of the per-room allow_list account data
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RoomAllowList {
+    /// Overrides the global allow_list if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allow_list: Option<Vec<String>>,
 }
 
+/// The account data event wrapping the RoomAllowList
+#[derive(Debug, Deserialize)]
+struct RoomAllowListEvent {
+    content: RoomAllowList,
+}
+
+#[derive(Debug)]
 struct HelpText {
     /// The command string that triggers this command
     command: String,
@@ -55,9 +155,62 @@ struct HelpText {
     args: Option<String>,
 }
 
+/// The mutable state of a bot
+/// The matrix sdk requires that anything used in the event handlers is 'static,
+/// so this is shared with the handlers through an Arc.
+#[derive(Debug)]
 struct State {
     /// Descriptions of the commands
     help: Vec<HelpText>,
+    /// The time that `run()` was started
+    start_time: Option<Instant>,
+    /// The latest sync token, if it hasn't been persisted yet
+    pending_sync_token: Option<String>,
+    /// The persisted session, kept in memory to avoid re-reading it from disk
+    session: Option<FullSession>,
+    /// The DM rooms used by `Bot::dm`, by the user they're with
+    dm_rooms: HashMap<OwnedUserId, OwnedRoomId>,
+    /// The conversations recorded by the text handler, see `max_session_turns`
+    sessions: SessionStore,
+    /// The most recent events the bot sent, by room
+    sent_events: HashMap<OwnedRoomId, VecDeque<OwnedEventId>>,
+    /// The queues of the sends to each room, see `ordered_sends`
+    /// A queue is removed once no sends are waiting on it.
+    send_queues: HashMap<OwnedRoomId, Arc<Mutex<()>>>,
+    /// The paginated messages that can be paged through, oldest first
+    paginated: VecDeque<paginate::PaginatedState>,
+    /// Messages held back by `text_debounce`, by room and sender
+    pending_texts: HashMap<(OwnedRoomId, OwnedUserId), PendingText>,
+    /// The latest command that ran for each room and sender, see `command_burst_window`
+    command_bursts: HashMap<(OwnedRoomId, OwnedUserId), CommandBurst>,
+    /// Every message the bot sent, see `Bot::recorded_messages`
+    #[cfg(any(test, feature = "testing"))]
+    recorded: Vec<testing::RecordedMessage>,
+    /// The messages since the last run, collected by `sync()` for `run()`, see `process_backfill`
+    backfill: replay::Backfill,
+    /// The sliding sync started by `sync()`, which `run()` continues
+    #[cfg(feature = "sliding-sync")]
+    sliding_sync: Option<matrix_sdk::SlidingSync>,
+}
+
+/// Messages from one sender that are collected by `text_debounce`
+#[derive(Debug)]
+struct PendingText {
+    /// The bodies of the messages, oldest first
+    bodies: Vec<String>,
+    /// The latest message
+    event: OriginalSyncRoomMessageEvent,
+    /// When the latest message was received
+    received: Instant,
+}
+
+/// A command that ran, and the commands dropped after it by `command_burst_window`
+#[derive(Debug)]
+struct CommandBurst {
+    /// When the command ran
+    started: Instant,
+    /// Whether a dropped command got the `one_at_a_time` reply
+    notified: bool,
 }
 
 /// The full session to persist.
@@ -76,9 +229,12 @@ struct FullSession {
     sync_token: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct Login {
     /// The homeserver URL to connect to
+    /// Can also be a server name like "example.org", the homeserver is then found through
+    /// the server's .well-known configuration.
     pub homeserver_url: String,
     /// The username to login with
     pub username: String,
@@ -87,7 +243,10 @@ pub struct Login {
 }
 
 /// The bot struct, holds all configuration needed for the bot
-#[derive(Debug, Clone)]
+/// See `BotConfig::builder` for a shorter way to create it, or `BotConfig::from_toml_file` to
+/// read it from a file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct BotConfig {
     /// Login info for matrix
     pub login: Login,
@@ -95,43 +254,472 @@ pub struct BotConfig {
     /// Defaults to login.username
     pub name: Option<String>,
     /// Allow list of which accounts we will respond to
-    pub allow_list: Option<String>,
+    /// Each entry is a regex, a sender is allowed if any of them match.
+    /// `Bot::login` fails with `HeadjackError::InvalidRegex` if an entry isn't a valid regex.
+    /// Can be overridden per room with `Bot::set_room_allow_list`
+    pub allow_list: Vec<String>,
+    /// The ID of a room whose topic holds the allow_list, one regex per line.
+    /// The allow_list is replaced whenever the topic changes, so it can be managed from any
+    /// client by the room's admins. An invalid or empty topic is logged and ignored.
+    /// Defaults to using the allow_list from the config
+    pub allow_list_room: Option<String>,
     /// Set the state directory to use
     /// Defaults to $XDG_STATE_HOME/username
     pub state_dir: Option<String>,
     /// Set the prefix for bot commands. Defaults to "!($name) "
+    /// The prefix is matched literally, it is not a regex.
+    /// A single character prefix like "!" is followed directly by the command, e.g. "!help".
+    /// Longer prefixes are separated from the command by a space, which is added if missing.
+    /// See `prefix_config` to change how strictly the prefix is matched.
+    /// Set it to `MENTION_PREFIX` to address the bot by mentioning it, e.g. "@bot:example.org help".
+    /// A prefix of "/" works, but many clients treat messages starting with a "/" as their own
+    /// commands and don't send them, so users may have to escape the "/".
+    /// An empty or whitespace only prefix is rejected, as every message would be a command.
     pub command_prefix: Option<String>,
+    /// Also accept commands addressed by mentioning the bot, in addition to `command_prefix`.
+    /// The bot then responds to both "!bot help" and "@bot:example.org help".
+    /// Defaults to false
+    pub mention_commands: Option<bool>,
+    /// How strictly the `command_prefix` is matched, e.g. to also accept "! help" or "!BOT help".
+    /// Defaults to the matching described for `command_prefix`
+    pub prefix_config: Option<PrefixConfig>,
     /// The Room size limit.
     /// Will refuse to join rooms exceeding this limit.
     pub room_size_limit: Option<usize>,
+    /// In rooms with more members than this, the text handler is only called for messages
+    /// that mention the bot. Smaller rooms, like DMs, get every message.
+    /// Defaults to calling the text handler for every message
+    pub mention_only_room_size: Option<u64>,
+    /// Register the builtin ping command, which replies with the latency and uptime.
+    /// Defaults to false
+    pub ping_command: Option<bool>,
+    /// Register the builtin rooms command, which lists the rooms the bot is in.
+    /// Only usable by admins, see `admin_power_level`.
+    /// Defaults to false
+    pub rooms_command: Option<bool>,
+    /// Register the builtin whoami command, which replies with the user ID, display name and
+    /// power level of the user that sent it. Helps users find their exact ID for the allow_list.
+    /// Defaults to false
+    pub whoami_command: Option<bool>,
+    /// Register the builtin ignore, unignore and ignored commands, to manage the users the bot
+    /// ignores from the chat. Only usable by admins, see `admin_power_level`.
+    /// Defaults to false
+    pub ignore_commands: Option<bool>,
+    /// Register the builtin purge command, which redacts the last messages the bot sent in the
+    /// room, e.g. after it spammed a room. Only usable by admins, see `admin_power_level`.
+    /// Defaults to false
+    pub purge_command: Option<bool>,
+    /// The power level a user needs in a room to use the admin commands in it.
+    /// Defaults to 50 (Moderator)
+    pub admin_power_level: Option<i64>,
+    /// The store backend used by the matrix client.
+    /// Defaults to a SQLite store in the state directory
+    pub store: Option<StoreConfig>,
+    /// Minimum interval between writes of the sync token to disk while running.
+    /// The latest token is always kept in memory and flushed when `run()` exits.
+    /// In config files it's seconds, or a string with units like "500ms".
+    /// Defaults to persisting after every sync
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub sync_token_persist_interval: Option<Duration>,
+    /// Register the builtin help command.
+    /// Defaults to true
+    pub builtin_help: Option<bool>,
+    /// The name of the builtin help command.
+    /// Defaults to "help"
+    pub help_command: Option<String>,
+    /// Don't send anything, only log what would have been sent.
+    /// Applies to all the send helpers on the Bot, but not to sends directly on a Room.
+    /// Useful for observing a new bot in a real room.
+    /// Defaults to false
+    pub read_only: Option<bool>,
+    /// Include the full message bodies in the logs.
+    /// Otherwise only event IDs and lengths are logged, to keep private content out of the logs.
+    /// Defaults to false
+    pub log_message_bodies: Option<bool>,
+    /// Refuse to send messages to unencrypted rooms.
+    /// Applies to all the send helpers on the Bot, which return an error instead of sending.
+    /// Defaults to false
+    pub require_encryption: Option<bool>,
+    /// Send the bot's messages to each room one at a time, in the order they were sent.
+    /// Without it, messages sent concurrently, e.g. by commands running at the same time, can
+    /// appear in any order. The cost is latency, as each message waits until the homeserver
+    /// has accepted the ones before it. Applies to all the send helpers on the Bot.
+    /// Defaults to false
+    pub ordered_sends: Option<bool>,
+    /// The user facing strings of the builtin commands, for translating them.
+    /// Defaults to English
+    pub strings: Option<Strings>,
+    /// Only respond to commands addressed to this bot by name, for rooms with several bots.
+    /// With a prefix of "!", the bot then responds to "!bot1 help" but not to "!help" or
+    /// "!bot2 help". The default prefix already contains the name, so this changes nothing there.
+    /// Defaults to false
+    pub multi_bot: Option<bool>,
+    /// How often a request is retried when the homeserver rate limits the bot or has an error.
+    /// Retries wait as long as the homeserver asks for, or back off exponentially.
+    /// Once they run out, rate limited sends fail with `HeadjackError::RateLimited`.
+    /// Applies to all requests, including syncs.
+    /// Defaults to retrying until the request succeeds
+    pub max_retries: Option<u64>,
+    /// How long a command may run before it's cancelled.
+    /// The command is dropped at its next await point, and the user gets the
+    /// `command_timed_out` reply from `strings`.
+    /// In config files it's seconds, or a string with units like "500ms".
+    /// Defaults to no timeout
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub command_timeout: Option<Duration>,
+    /// Only run the first command a user sends in a room within this long, e.g. to ignore
+    /// accidental double submits. The later commands are dropped, and the first one dropped
+    /// gets the `one_at_a_time` reply from `strings`. The window starts at the command that ran.
+    /// In config files it's seconds, or a string with units like "500ms".
+    /// Defaults to running every command
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub command_burst_window: Option<Duration>,
+    /// The maximum number of commands that run at the same time.
+    /// Commands sent while the limit is reached aren't run, the user gets the `busy` reply from
+    /// `strings` instead. Only read at startup.
+    /// Defaults to no limit
+    pub max_concurrent_commands: Option<usize>,
+    /// Only handle messages in rooms the bot's account has tagged with this (namespace, tag).
+    /// E.g. ("dev.headjack", "enabled") requires the "dev.headjack.enabled" tag, so the bot can
+    /// be enabled per room by tagging it.
+    /// Defaults to handling all joined rooms
+    pub required_room_tag: Option<(String, String)>,
+    /// Also pass notices (`m.notice`) to the commands and the text handler.
+    /// Notices are usually sent by other bots, so they're ignored by default to keep bots from
+    /// responding to each other in a loop. Enable it for bots that are meant to be chained.
+    /// Defaults to false
+    pub handle_notices: Option<bool>,
+    /// Record the conversation in each room, keeping this many messages, see `Bot::session`.
+    /// The messages passed to the text handler and the messages the bot sends are recorded,
+    /// and persisted in the state directory.
+    /// Defaults to not recording anything
+    pub max_session_turns: Option<usize>,
+    /// The User-Agent sent to the homeserver, so its admins can tell which bot is making requests.
+    /// Defaults to "headjack/<version>"
+    pub user_agent: Option<String>,
+    /// The proxy to connect to the homeserver through, e.g. "http://proxy.example.org:8080".
+    /// Defaults to the proxy environment variables, e.g. `HTTPS_PROXY` and `NO_PROXY`
+    pub proxy: Option<String>,
+    /// INSECURE: Don't verify the TLS certificate of the homeserver.
+    /// Only meant for testing against a local homeserver with a self-signed certificate, as it
+    /// allows anyone on the network to intercept the bot's traffic.
+    /// Defaults to false
+    pub accept_invalid_certs: Option<bool>,
+    /// Customize the matrix client beyond what the other fields allow, e.g. its request config.
+    /// Called with headjack's builder right before the client is built, so it can override
+    /// any of the settings, including the store. Can only be set in code.
+    /// Defaults to using headjack's builder as is
+    #[serde(skip)]
+    pub client_builder_hook: Option<ClientBuilderHook>,
+    /// Leave presence, typing notifications and read receipts out of syncs.
+    /// The bot doesn't use them, so there's no need to download and parse them. Event handlers
+    /// for them aren't called anymore.
+    /// Defaults to false
+    pub ignore_ephemeral: Option<bool>,
+    /// Handle the messages that arrived while the bot was offline, e.g. for digest bots.
+    /// This replaces the default of skipping them: normally `sync()` catches up before the
+    /// handlers are registered, so those messages are never handled. With this set, `sync()`
+    /// collects them, paginating back to the last sync for rooms with many new messages, and
+    /// `run()` passes them to the message handlers oldest first before syncing further.
+    /// Only applies when restoring a session with a sync token. Other events, e.g. invites,
+    /// are still skipped. Can't be used with `sliding_sync`.
+    /// Defaults to false
+    pub process_backfill: Option<bool>,
+    /// Sync with sliding sync instead of `/sync`, which loads the rooms in batches and speeds up
+    /// the startup of accounts in thousands of rooms.
+    /// Needs the `sliding-sync` feature and a sliding sync proxy. Experimental, like its support
+    /// in matrix-sdk. The position isn't persisted, so `process_backfill` can't be used with it.
+    /// Defaults to false
+    pub sliding_sync: Option<bool>,
+    /// The URL of the sliding sync proxy, see `sliding_sync`
+    /// Defaults to the proxy advertised in the homeserver's .well-known
+    pub sliding_sync_proxy: Option<String>,
+    /// Collect the messages a user sends in quick succession, and pass them to the text handler
+    /// as one message, with the bodies joined by newlines.
+    /// A message is held until the user hasn't sent another one in the room for this long.
+    /// In config files it's seconds, or a string with units like "500ms".
+    /// Defaults to calling the text handler for every message right away
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub text_debounce: Option<Duration>,
+}
+
+/// A function that customizes the client builder, see `client_builder_hook`
+#[derive(Clone)]
+pub struct ClientBuilderHook(Arc<dyn Fn(ClientBuilder) -> ClientBuilder + Send + Sync>);
+
+impl ClientBuilderHook {
+    /// Wrap the function, which gets headjack's builder and returns the one to build
+    pub fn new(hook: impl Fn(ClientBuilder) -> ClientBuilder + Send + Sync + 'static) -> Self {
+        ClientBuilderHook(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for ClientBuilderHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientBuilderHook")
+    }
+}
+
+/// How the `command_prefix` is matched in messages
+/// Doesn't apply to commands addressed with a mention of the bot.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PrefixConfig {
+    /// Whether whitespace separates the prefix from the command.
+    /// Defaults to `Forbidden` for a single character prefix like "!", so that "!help" works,
+    /// and to `Required` for longer prefixes, so that "!bot help" works but "!bothelp" doesn't
+    pub space: Option<PrefixSpace>,
+    /// Whether the case of the prefix must match, e.g. whether "!BOT help" is a command for
+    /// a prefix of "!bot". Commands are always case sensitive.
+    /// Defaults to true
+    pub case_sensitive: Option<bool>,
+}
+
+impl PrefixConfig {
+    /// Split a message that starts with the command prefix into the command and its arguments
+    /// This is how the bot matches its `command_prefix`, so with the bot's prefix and config it
+    /// agrees with what the bot treats as a command. The arguments are trimmed, see `get_args`.
+    pub fn split_command<'a>(
+        &self,
+        command_prefix: &str,
+        text: &'a str,
+    ) -> Option<(&'a str, &'a str)> {
+        // A trailing space in the prefix is the separator, which is matched separately
+        let trimmed = command_prefix.trim_end();
+        let space = self.space.unwrap_or(
+            if trimmed.len() < command_prefix.len() || trimmed.chars().count() > 1 {
+                PrefixSpace::Required
+            } else {
+                PrefixSpace::Forbidden
+            },
+        );
+        let rest = if self.case_sensitive.unwrap_or(true) {
+            text.strip_prefix(trimmed)?
+        } else {
+            strip_prefix_ignore_case(text, trimmed)?
+        };
+        let command = rest.trim_start();
+        let spaced = command.len() < rest.len();
+        let allowed = match space {
+            PrefixSpace::Forbidden => !spaced,
+            PrefixSpace::Required => spaced,
+            PrefixSpace::Optional => true,
+        };
+        // Without a space the command must start with a letter or number, so that messages
+        // like "!!!" or "*emphasis*" aren't mistaken for commands
+        if !allowed || (!spaced && !command.starts_with(char::is_alphanumeric)) {
+            return None;
+        }
+        split_words(command)
+    }
+}
+
+/// Whether whitespace separates the command prefix from the command, see `PrefixConfig`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrefixSpace {
+    /// The command follows the prefix directly, e.g. "!help"
+    Forbidden,
+    /// The command is separated from the prefix by whitespace, e.g. "! help"
+    Required,
+    /// Either way, e.g. both "!help" and "! help"
+    Optional,
+}
+
+/// Summary of a room the bot has joined
+#[derive(Debug, Clone)]
+pub struct RoomSummary {
+    /// The ID of the room
+    pub room_id: OwnedRoomId,
+    /// The display name of the room
+    pub name: String,
+    /// The number of joined members
+    pub members: u64,
+    /// Whether the room is encrypted
+    pub encrypted: bool,
+}
+
+/// The result of leaving multiple rooms
+#[derive(Debug, Default)]
+pub struct LeaveSummary {
+    /// The rooms that were left
+    pub left: Vec<OwnedRoomId>,
+    /// The rooms that failed to be left, with the error
+    pub failed: Vec<(OwnedRoomId, Error)>,
+}
+
+/// The store backend used for the matrix client state and encryption keys
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StoreConfig {
+    /// Persist everything to an encrypted SQLite database
+    Sqlite {
+        /// Path of the database directory.
+        /// Defaults to a random subfolder of the state directory
+        path: Option<PathBuf>,
+        /// Passphrase used to encrypt the database.
+        /// Defaults to a random passphrase
+        passphrase: Option<String>,
+        /// Where to keep the passphrase.
+        /// Defaults to storing it in plaintext in the session file
+        passphrase_store: Option<PassphraseStore>,
+    },
+    /// Keep everything in memory.
+    /// Nothing is persisted, so the bot logs in as a new device every time it starts.
+    /// Useful for ephemeral bots and for testing.
+    Memory,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Sqlite {
+            path: None,
+            passphrase: None,
+            passphrase_store: None,
+        }
+    }
+}
+
+/// Where the passphrase of the SQLite store is kept
+///
+/// The session file only holds a reference to the passphrase for the non-default options.
+/// With `SessionFile` the passphrase is stored in plaintext next to the access token,
+/// so anyone who can read the session file can also decrypt the store.
+/// Prefer one of the other options on shared hosts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PassphraseStore {
+    /// Store the passphrase in plaintext in the session file
+    #[default]
+    SessionFile,
+    /// Read the passphrase from an environment variable
+    /// The variable must be set every time the bot starts.
+    Env {
+        /// Name of the environment variable
+        var: String,
+    },
+    /// Store the passphrase in the OS keyring
+    #[cfg(feature = "keyring")]
+    Keyring {
+        /// The keyring service name
+        service: String,
+        /// The keyring user name
+        user: String,
+    },
+}
+
+impl PassphraseStore {
+    /// Get the passphrase to use for a new store
+    /// Only the environment variable overrides the configured or generated passphrase.
+    fn passphrase(&self, passphrase: String) -> Result<String, HeadjackError> {
+        match self {
+            PassphraseStore::Env { var } => std::env::var(var)
+                .map_err(|e| HeadjackError::Passphrase(format!("unable to read ${}: {}", var, e))),
+            _ => Ok(passphrase),
+        }
+    }
+
+    /// Save the passphrase
+    /// Returns the passphrase if it needs to be stored in the session file
+    fn save(&self, passphrase: &str) -> Result<Option<String>, HeadjackError> {
+        match self {
+            PassphraseStore::SessionFile => Ok(Some(passphrase.to_owned())),
+            PassphraseStore::Env { .. } => Ok(None),
+            #[cfg(feature = "keyring")]
+            PassphraseStore::Keyring { service, user } => {
+                keyring::Entry::new(service, user)?.set_password(passphrase)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Load the passphrase for an existing store
+    fn load(&self, session_passphrase: Option<String>) -> Result<String, HeadjackError> {
+        match self {
+            PassphraseStore::SessionFile => session_passphrase.ok_or_else(|| {
+                HeadjackError::Passphrase("the session file is missing the passphrase".to_string())
+            }),
+            PassphraseStore::Env { var } => std::env::var(var)
+                .map_err(|e| HeadjackError::Passphrase(format!("unable to read ${}: {}", var, e))),
+            #[cfg(feature = "keyring")]
+            PassphraseStore::Keyring { service, user } => {
+                Ok(keyring::Entry::new(service, user)?.get_password()?)
+            }
+        }
+    }
 }
 
 /// A Matrix Bot
 #[derive(Debug, Clone)]
 pub struct Bot {
+    /// State shared with the event handlers.
+    /// Clones of the bot share the same state.
+    state: Arc<Mutex<State>>,
+
     /// Configuration for the bot.
-    config: BotConfig,
+    /// Shared so that it can be reloaded while running, see `reload_config`.
+    config: Arc<RwLock<BotConfig>>,
 
     /// The current sync token.
     sync_token: Option<String>,
 
     /// The matrix client.
     client: Option<Client>,
+
+    /// Limits how many commands run at once, see `max_concurrent_commands`.
+    command_permits: Option<Arc<Semaphore>>,
+
+    /// Set once the first sync has finished, see `is_initial_sync_complete`.
+    initial_sync_complete: Arc<AtomicBool>,
+
+    /// The compiled allow_list, or the error compiling it, updated by `reload_config`.
+    allow_list: Arc<RwLock<Result<Arc<RegexSet>, regex::Error>>>,
+
+    /// The compiled allow_list overrides of the rooms, None for rooms without one.
+    /// Dropped when the room's account data changes, see `watch_room_allow_lists`.
+    room_allow_lists: Arc<RwLock<HashMap<OwnedRoomId, Option<Arc<RegexSet>>>>>,
+
+    /// The message handlers, for the events that didn't arrive through the sync.
+    replay_handlers: replay::ReplayHandlers,
 }
 
 impl Bot {
     pub async fn new(config: BotConfig) -> Self {
-        let bot = Bot {
-            config,
+        let command_permits = config
+            .max_concurrent_commands
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let allow_list = RegexSet::new(&config.allow_list).map(Arc::new);
+        if let Err(e) = &allow_list {
+            error!("Invalid allow_list, no one is allowed: {}", e);
+        }
+        Bot {
+            state: Arc::new(Mutex::new(State {
+                help: Vec::new(),
+                start_time: None,
+                pending_sync_token: None,
+                session: None,
+                dm_rooms: HashMap::new(),
+                sessions: SessionStore::default(),
+                sent_events: HashMap::new(),
+                send_queues: HashMap::new(),
+                paginated: VecDeque::new(),
+                pending_texts: HashMap::new(),
+                command_bursts: HashMap::new(),
+                #[cfg(any(test, feature = "testing"))]
+                recorded: Vec::new(),
+                backfill: Vec::new(),
+                #[cfg(feature = "sliding-sync")]
+                sliding_sync: None,
+            })),
+            config: Arc::new(RwLock::new(config)),
             sync_token: None,
             client: None,
-        };
-        // Initialize the global state for the bot if it doesn't exist
-        let mut global_state = GLOBAL_STATE.lock().await;
-        global_state
-            .entry(bot.name())
-            .or_insert_with(|| Mutex::new(State { help: Vec::new() }));
-        bot
+            command_permits,
+            initial_sync_complete: Arc::new(AtomicBool::new(false)),
+            allow_list: Arc::new(RwLock::new(allow_list)),
+            room_allow_lists: Arc::default(),
+            replay_handlers: replay::ReplayHandlers::default(),
+        }
     }
 
     /// Get the path to the session file
@@ -139,113 +727,530 @@ impl Bot {
         self.state_dir().join("session")
     }
 
+    /// Whether the session is persisted to disk
+    /// The in-memory store can't be restored, so we login fresh every time with it.
+    fn persists_session(&self) -> bool {
+        !matches!(self.config().store, Some(StoreConfig::Memory))
+    }
+
+    /// The settings for the HTTP client of the matrix client
+    fn http_settings(&self) -> HttpSettings {
+        let config = self.config();
+        let request_config = match config.max_retries {
+            Some(max_retries) => RequestConfig::new().retry_limit(max_retries),
+            None => RequestConfig::new(),
+        };
+        HttpSettings {
+            request_config,
+            user_agent: config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| format!("headjack/{}", env!("CARGO_PKG_VERSION"))),
+            proxy: config.proxy.clone(),
+            accept_invalid_certs: config.accept_invalid_certs.unwrap_or(false),
+            builder_hook: config.client_builder_hook.clone(),
+            #[cfg(feature = "sliding-sync")]
+            sliding_sync_proxy: config.sliding_sync_proxy.clone(),
+        }
+    }
+
     /// Login to the matrix server
     /// Performs everything needed to login or relogin
-    pub async fn login(&mut self) -> anyhow::Result<()> {
+    pub async fn login(&mut self) -> Result<(), HeadjackError> {
+        // Fail at startup rather than ignoring everyone
+        if let Err(e) = &*self.allow_list.read().expect("allow_list lock poisoned") {
+            return Err(e.clone().into());
+        }
+        check_command_prefix(self.config().command_prefix.as_deref())?;
+        check_sliding_sync(&self.config())?;
         let state_dir = self.state_dir();
         let session_file = self.session_file();
 
-        let (client, sync_token) = if session_file.exists() {
-            restore_session(&session_file).await?
+        let store = self.config().store.clone().unwrap_or_default();
+        let login_config = self.config().login.clone();
+
+        let http = self.http_settings();
+
+        let (client, session) = if self.persists_session() && session_file.exists() {
+            let (client, session) = restore_session(&session_file, &http).await?;
+            (client, Some(session))
         } else {
-            (
-                login(
-                    &state_dir,
-                    &session_file,
-                    &self.config.login.homeserver_url,
-                    &self.config.login.username,
-                    &self.config.login.password,
-                )
-                .await?,
-                None,
+            login(
+                &state_dir,
+                self.persists_session().then_some(session_file.as_path()),
+                &store,
+                &login_config.homeserver_url,
+                &login_config.username,
+                &login_config.password,
+                &http,
             )
+            .await?
         };
 
-        self.sync_token = sync_token;
+        self.sync_token = session.as_ref().and_then(|s| s.sync_token.clone());
+        {
+            // Keep the session in memory so that we don't need to re-read it to persist
+            self.state.lock().await.session = session;
+        }
         self.client = Some(client);
+        self.watch_room_allow_lists()?;
 
         Ok(())
     }
 
     /// Sync to the current state of the homeserver
-    pub async fn sync(&mut self) -> anyhow::Result<()> {
-        let client = self.client.as_ref().expect("client not initialized");
+    /// Messages that arrived since the last sync are skipped, unless `process_backfill` is set.
+    pub async fn sync(&mut self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
 
-        // Enable room members lazy-loading, it will speed up the initial sync a lot
-        // with accounts in lots of rooms.
-        // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
-        let filter = FilterDefinition::with_lazy_loading();
-        let mut sync_settings = SyncSettings::default().filter(filter.into());
+        #[cfg(feature = "sliding-sync")]
+        if self.config().sliding_sync.unwrap_or(false) {
+            return self.sliding_sync_initial().await;
+        }
+
+        let mut sync_settings = SyncSettings::default().filter(self.sync_filter().into());
 
         // If we've already synced through a certain point, we'll sync the latest.
         if let Some(sync_token) = &self.sync_token {
             sync_settings = sync_settings.token(sync_token);
         }
 
+        info!("Starting the initial sync, this can take minutes for accounts in many rooms");
+        let started = Instant::now();
         loop {
-            match client.sync_once(sync_settings.clone()).await {
+            match sync_once_with_progress(client, sync_settings.clone(), started).await {
                 Ok(response) => {
+                    info!(
+                        "Initial sync done in {}, in {} rooms",
+                        format_duration(started.elapsed()),
+                        response.rooms.join.len()
+                    );
+                    self.initial_sync_complete.store(true, Ordering::Relaxed);
+                    if let Some(since) = self.sync_token.as_deref() {
+                        if self.config().process_backfill.unwrap_or(false) {
+                            self.collect_backfill(&response, since).await?;
+                        }
+                    }
                     self.sync_token = Some(response.next_batch.clone());
-                    persist_sync_token(&self.session_file(), response.next_batch.clone()).await?;
+                    self.persist_sync_token(response.next_batch.clone()).await?;
                     break;
                 }
+                Err(error) if is_fatal_sync_error(&error) => {
+                    return Err(self.sync_error(error).await);
+                }
                 Err(error) => {
-                    error!("An error occurred during initial sync: {error}");
-                    error!("Trying again…");
+                    trace!("Initial sync error: {error:?}");
+                    error!(
+                        "The initial sync failed, trying again: {}",
+                        describe_sync_error(&error)
+                    );
                 }
             }
         }
         Ok(())
     }
 
+    /// The filter for syncs with the homeserver
+    fn sync_filter(&self) -> FilterDefinition {
+        // Enable room members lazy-loading, it will speed up the initial sync a lot
+        // with accounts in lots of rooms.
+        // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
+        let mut filter = FilterDefinition::with_lazy_loading();
+        if self.config().ignore_ephemeral.unwrap_or(false) {
+            filter.presence = Filter::ignore_all();
+            filter.room.ephemeral = RoomEventFilter::ignore_all();
+        }
+        filter
+    }
+
     /// Create the help command
     /// This adds a command that prints the help
-    async fn register_help_command(&self) {
-        let name = self.name();
-        let command_prefix = self.command_prefix();
+    async fn register_help_command(&self) -> Result<(), HeadjackError> {
+        let bot = self.clone();
+        let help_command = self.help_command();
+        {
+            // Don't clobber a user registered command with the same name
+            let state = self.state.lock().await;
+            if state.help.iter().any(|h| h.command == help_command) {
+                warn!(
+                    "A command named '{}' is already registered, skipping the builtin help command",
+                    help_command
+                );
+                return Ok(());
+            }
+        }
         self.register_text_command(
-            "help",
+            &self.help_command(),
             None,
-            Some("Show this message".to_string()),
-            |_, _, room| async move {
-                let global_state = GLOBAL_STATE.lock().await;
-                let state = global_state.get(&name).unwrap();
-                let state = state.lock().await;
-                let help = &state.help;
-                let mut response = format!("`{}help`\n\nAvailable commands:", command_prefix);
-
-                for h in help {
-                    response.push_str(&format!("\n`{}{}", command_prefix, h.command));
-                    if let Some(args) = &h.args {
-                        response.push_str(&format!(" {}", args));
-                    }
-                    if let Some(short) = &h.short {
-                        response.push_str(&format!("` - {}", short));
-                    }
+            Some(self.strings().help_description),
+            |message, room| async move {
+                let command_prefix = bot.command_prefix();
+                let header = bot.strings().help_header;
+                let header = format!("`{}{}`\n\n{}", command_prefix, help_command, header);
+                let lines: Vec<String> = {
+                    let state = bot.state.lock().await;
+                    state
+                        .help
+                        .iter()
+                        .map(|h| {
+                            let mut line = format!("`{}{}", command_prefix, h.command);
+                            if let Some(args) = &h.args {
+                                line.push_str(&format!(" {}", args));
+                            }
+                            if let Some(short) = &h.short {
+                                line.push_str(&format!("` - {}", short));
+                            }
+                            line
+                        })
+                        .collect()
+                };
+                // Long help is paged through instead of flooding the room
+                let help = PaginatedMessage::from_lines(&header, lines, HELP_PAGE_SIZE);
+                bot.send_paginated(&room, message.thread_root(), help)
+                    .await
+                    .map_err(|_| ())?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Create the ping command
+    /// Replies with the latency of the command message and the uptime of the bot
+    async fn register_ping_command(&self) -> Result<(), HeadjackError> {
+        let bot = self.clone();
+        self.register_text_command(
+            "ping",
+            None,
+            Some(self.strings().ping_description),
+            |message, room| async move {
+                // The latency is the time between the server receiving the message and now.
+                // Clock skew between the server and the bot can make this negative.
+                let now: i64 = MilliSecondsSinceUnixEpoch::now().get().into();
+                let sent: i64 = message.event.origin_server_ts.get().into();
+                let latency = now - sent;
+                let uptime = {
+                    let state = bot.state.lock().await;
+                    state.start_time.map(|start| start.elapsed())
+                };
+                let strings = bot.strings();
+                let mut response = strings
+                    .ping_response
+                    .replace("{latency}", &latency.to_string());
+                if let Some(uptime) = uptime {
+                    response.push_str(
+                        &strings
+                            .ping_uptime
+                            .replace("{uptime}", &format_duration(uptime)),
+                    );
                 }
-                room.send(RoomMessageEventContent::text_markdown(response))
+                bot.send_in_thread(
+                    &room,
+                    message.thread_root(),
+                    RoomMessageEventContent::text_plain(response),
+                )
+                .await
+                .map_err(|_| ())?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Create the rooms command
+    /// Lists the rooms the bot has joined, only usable by room admins
+    async fn register_rooms_command(&self) -> Result<(), HeadjackError> {
+        let bot = self.clone();
+        self.register_text_command(
+            "rooms",
+            None,
+            Some(self.strings().rooms_description),
+            |message, room| async move {
+                if !bot.is_admin(&room, &message.sender).await {
+                    bot.send_in_thread(
+                        &room,
+                        message.thread_root(),
+                        RoomMessageEventContent::text_plain(bot.strings().not_allowed),
+                    )
                     .await
                     .map_err(|_| ())?;
+                    return Ok(());
+                }
+                let rooms = bot.joined_rooms_summary().await;
+                let mut response = bot
+                    .strings()
+                    .rooms_header
+                    .replace("{count}", &rooms.len().to_string());
+                for summary in rooms {
+                    response.push_str(&format!(
+                        "\n- {} (`{}`): {} members{}",
+                        escape_markdown(&summary.name),
+                        summary.room_id,
+                        summary.members,
+                        if summary.encrypted { ", encrypted" } else { "" }
+                    ));
+                }
+                bot.send_in_thread(
+                    &room,
+                    message.thread_root(),
+                    RoomMessageEventContent::text_markdown(response),
+                )
+                .await
+                .map_err(|_| ())?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Create the whoami command
+    /// Replies with the user ID, display name and power level of the sender in the room
+    async fn register_whoami_command(&self) -> Result<(), HeadjackError> {
+        let bot = self.clone();
+        self.register_text_command(
+            "whoami",
+            None,
+            Some(self.strings().whoami_description),
+            |message, room| async move {
+                let member = room.get_member(&message.sender).await.map_err(|_| ())?;
+                // The display name is chosen by the user, so it's shown as is rather than as
+                // Markdown that could add links or mentions to the reply
+                let display_name = escape_markdown(
+                    member
+                        .as_ref()
+                        .and_then(|member| member.display_name())
+                        .unwrap_or(message.sender.localpart()),
+                );
+                let power_level = member.map_or(0, |member| member.power_level());
+                let response = bot
+                    .strings()
+                    .whoami_response
+                    .replace("{user_id}", message.sender.as_str())
+                    .replace("{display_name}", &display_name)
+                    .replace("{power_level}", &power_level.to_string());
+                bot.reply(
+                    &room,
+                    &message,
+                    RoomMessageEventContent::text_markdown(response),
+                )
+                .await
+                .map_err(|_| ())?;
                 Ok(())
             },
         )
-        .await;
+        .await
+    }
+
+    /// Create the purge command
+    /// Redacts the last N messages the bot sent in the room, only usable by room admins
+    async fn register_purge_command(&self) -> Result<(), HeadjackError> {
+        let bot = self.clone();
+        self.register_text_command(
+            "purge",
+            Some("<count>".to_string()),
+            Some(self.strings().purge_description),
+            |message, room| async move {
+                let strings = bot.strings();
+                if !bot.is_admin(&room, &message.sender).await {
+                    bot.reply_text(&room, &message, strings.not_allowed).await;
+                    return Ok(());
+                }
+                let Ok(count) = message.args.parse::<usize>() else {
+                    bot.reply_text(&room, &message, strings.purge_usage).await;
+                    return Ok(());
+                };
+                let redacted = match bot.purge_own_messages(&room, count).await {
+                    Ok(redacted) => redacted,
+                    Err(e) => {
+                        error!("Error purging messages in {}: {:?}", room.room_id(), e);
+                        return Err(());
+                    }
+                };
+                let response = strings.purge_done.replace("{count}", &redacted.to_string());
+                bot.reply_text(&room, &message, response).await;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Get the name of the room as clients show it, for logs and responses
+    /// Falls back to the room ID if the name can't be computed.
+    pub async fn room_display_name(&self, room: &Room) -> String {
+        match room.display_name().await {
+            Ok(name) => name.to_string(),
+            Err(_) => room.room_id().to_string(),
+        }
+    }
+
+    /// Get a summary of all the rooms the bot has joined
+    pub async fn joined_rooms_summary(&self) -> Vec<RoomSummary> {
+        let mut summaries = Vec::new();
+        let Some(client) = self.try_client() else {
+            return summaries;
+        };
+        for room in client.joined_rooms() {
+            summaries.push(RoomSummary {
+                room_id: room.room_id().to_owned(),
+                name: self.room_display_name(&room).await,
+                members: room.joined_members_count(),
+                encrypted: self.is_room_encrypted(&room).await,
+            });
+        }
+        summaries
+    }
+
+    /// Leave all joined rooms matching the predicate
+    /// The rooms are left concurrently, and the result of each leave is collected.
+    /// Nothing is left if the bot isn't logged in yet.
+    pub async fn leave_rooms_where<P>(&self, predicate: P) -> LeaveSummary
+    where
+        P: Fn(&Room) -> bool,
+    {
+        let Some(client) = self.try_client() else {
+            return LeaveSummary::default();
+        };
+        let rooms: Vec<Room> = client
+            .joined_rooms()
+            .into_iter()
+            .filter(|room| predicate(room))
+            .collect();
+        let results = join_all(rooms.iter().map(|room| room.leave())).await;
+
+        let mut summary = LeaveSummary::default();
+        for (room, result) in rooms.iter().zip(results) {
+            match result {
+                Ok(()) => {
+                    info!("Left room {}", room.room_id());
+                    summary.left.push(room.room_id().to_owned());
+                }
+                Err(e) => {
+                    error!("Error leaving room {}: {:?}", room.room_id(), e);
+                    summary.failed.push((room.room_id().to_owned(), e));
+                }
+            }
+        }
+        summary
+    }
+
+    /// Check if the user is allowed to use the admin commands in the room
+    /// They must be on the allow_list and have at least the admin power level in the room.
+    pub async fn is_admin(&self, room: &Room, user_id: &UserId) -> bool {
+        let Some(username) = self.user_id() else {
+            return false;
+        };
+        if !self
+            .is_allowed_in_room(room, user_id.as_str(), username.as_str())
+            .await
+        {
+            return false;
+        }
+        let required = self.config().admin_power_level.unwrap_or(50);
+        match room.get_member(user_id).await {
+            Ok(Some(member)) => member.power_level() >= required,
+            _ => false,
+        }
+    }
+
+    /// Invite a user to the room
+    /// Succeeds without doing anything if the user is already joined or invited.
+    pub async fn invite(&self, room: &Room, user_id: &UserId) -> Result<(), HeadjackError> {
+        if let Some(member) = room.get_member_no_sync(user_id).await? {
+            if matches!(
+                member.membership(),
+                MembershipState::Join | MembershipState::Invite
+            ) {
+                return Ok(());
+            }
+        }
+        if self.config().read_only.unwrap_or(false) {
+            info!("Read only, not inviting {} to {}", user_id, room.room_id());
+            return Ok(());
+        }
+        room.invite_user_by_id(user_id).await?;
+        Ok(())
+    }
+
+    /// Get the topic of the room, if it has one
+    pub fn room_topic(&self, room: &Room) -> Option<String> {
+        room.topic()
+    }
+
+    /// Get the name of the room, if it has one
+    /// Unlike `room_display_name` this doesn't fall back to the members or the room ID.
+    pub fn room_name(&self, room: &Room) -> Option<String> {
+        room.name()
+    }
+
+    /// Set the topic of the room
+    /// Fails with `HeadjackError::MissingPermission` if the bot's power level is too low.
+    pub async fn set_room_topic(&self, room: &Room, topic: &str) -> Result<(), HeadjackError> {
+        if !self
+            .prepare_state_change(room, StateEventType::RoomTopic)
+            .await?
+        {
+            return Ok(());
+        }
+        room.set_room_topic(topic).await?;
+        Ok(())
+    }
+
+    /// Set the name of the room
+    /// Fails with `HeadjackError::MissingPermission` if the bot's power level is too low.
+    pub async fn set_room_name(&self, room: &Room, name: &str) -> Result<(), HeadjackError> {
+        if !self
+            .prepare_state_change(room, StateEventType::RoomName)
+            .await?
+        {
+            return Ok(());
+        }
+        room.set_name(name.to_string()).await?;
+        Ok(())
+    }
+
+    /// Check that the bot may send the state event to the room
+    /// Returns false if it shouldn't be sent because the bot is read only.
+    async fn prepare_state_change(
+        &self,
+        room: &Room,
+        event_type: StateEventType,
+    ) -> Result<bool, HeadjackError> {
+        let user_id = self.user_id().ok_or(HeadjackError::NotLoggedIn)?;
+        let member = room.get_member(&user_id).await?;
+        if !member.is_some_and(|member| member.can_send_state(event_type.clone())) {
+            return Err(HeadjackError::MissingPermission {
+                room_id: room.room_id().to_owned(),
+                event_type,
+            });
+        }
+        if self.config().read_only.unwrap_or(false) {
+            info!(
+                "Read only, not sending {} to {}",
+                event_type,
+                room.room_id()
+            );
+            return Ok(false);
+        }
+        Ok(true)
     }
 
     /// Adds a callback to join rooms we've been invited to
     /// Ignores invites from anyone who is not on the allow_list
-    pub fn join_rooms(&self) {
-        let client = self.client.as_ref().expect("client not initialized");
-        let allow_list = self.config.allow_list.clone();
-        let username = self.full_name();
-        let room_size_limit = self.config.room_size_limit;
+    pub fn join_rooms(&self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let bot = self.clone();
+        let username = self.full_name()?;
         client.add_event_handler(
             move |room_member: StrippedRoomMemberEvent, client: Client, room: Room| async move {
-                if room_member.state_key != client.user_id().unwrap() {
+                if client.user_id() != Some(&room_member.state_key) {
                     // the invite we've seen isn't for us, but for someone else. ignore
                     return;
                 }
-                if !is_allowed(allow_list, room_member.sender.as_str(), &username) {
+                let room_size_limit = bot.config().room_size_limit;
+                if !bot
+                    .is_allowed_in_room(&room, room_member.sender.as_str(), &username)
+                    .await
+                {
                     // Sender is not on the allowlist
                     return;
                 }
@@ -263,12 +1268,14 @@ impl Bot {
                         // retry autojoin due to synapse sending invites, before the
                         // invited user can join for more information see
                         // https://github.com/matrix-org/synapse/issues/4345
+                        let wait = with_jitter(delay);
                         warn!(
-                            "Failed to join room {} ({err:?}), retrying in {delay}s",
-                            room.room_id()
+                            "Failed to join room {} ({err:?}), retrying in {:.1}s",
+                            room.room_id(),
+                            wait.as_secs_f64()
                         );
 
-                        sleep(Duration::from_secs(delay)).await;
+                        sleep(wait).await;
                         delay *= 2;
 
                         if delay > 3600 {
@@ -276,42 +1283,48 @@ impl Bot {
                             break;
                         }
                     }
+                    let name = bot.room_display_name(&room).await;
+                    if !wait_for_join(&room).await {
+                        error!("Joining room '{}' didn't complete", name);
+                        return;
+                    }
                     // Immediately leave if the room is too large
                     if is_room_too_large(&room, room_size_limit).await {
-                        warn!(
-                            "Room {} has too many members, refusing to join",
-                            room.room_id()
-                        );
+                        warn!("Room '{}' has too many members, refusing to join", name);
                         if let Err(e) = room.leave().await {
                             error!("Error leaving room: {:?}", e);
                         }
                         return;
                     }
-                    info!("Successfully joined room {}", room.room_id());
+                    info!("Successfully joined room '{}'", name);
                 });
             },
         );
+        Ok(())
     }
 
     /// Adds a callback to join rooms we've been invited to
     /// Ignores invites from anyone who is not on the allow_list
     /// Calls the callback each time a room is joined
-    pub fn join_rooms_callback<F, Fut>(&self, callback: Option<F>)
+    pub fn join_rooms_callback<F, Fut>(&self, callback: Option<F>) -> Result<(), HeadjackError>
     where
         F: FnOnce(Room) -> Fut + Send + 'static + Clone + Sync,
         Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
     {
-        let client = self.client.as_ref().expect("client not initialized");
-        let allow_list = self.config.allow_list.clone();
-        let username = self.full_name();
-        let room_size_limit = self.config.room_size_limit;
+        let client = self.client()?;
+        let bot = self.clone();
+        let username = self.full_name()?;
         client.add_event_handler(
             move |room_member: StrippedRoomMemberEvent, client: Client, room: Room| async move {
-                if room_member.state_key != client.user_id().unwrap() {
+                if client.user_id() != Some(&room_member.state_key) {
                     // the invite we've seen isn't for us, but for someone else. ignore
                     return;
                 }
-                if !is_allowed(allow_list, room_member.sender.as_str(), &username) {
+                let room_size_limit = bot.config().room_size_limit;
+                if !bot
+                    .is_allowed_in_room(&room, room_member.sender.as_str(), &username)
+                    .await
+                {
                     // Sender is not on the allowlist
                     return;
                 }
@@ -329,12 +1342,14 @@ impl Bot {
                         // retry autojoin due to synapse sending invites, before the
                         // invited user can join for more information see
                         // https://github.com/matrix-org/synapse/issues/4345
+                        let wait = with_jitter(delay);
                         warn!(
-                            "Failed to join room {} ({err:?}), retrying in {delay}s",
-                            room.room_id()
+                            "Failed to join room {} ({err:?}), retrying in {:.1}s",
+                            room.room_id(),
+                            wait.as_secs_f64()
                         );
 
-                        sleep(Duration::from_secs(delay)).await;
+                        sleep(wait).await;
                         delay *= 2;
 
                         if delay > 3600 {
@@ -342,18 +1357,20 @@ impl Bot {
                             break;
                         }
                     }
+                    let name = bot.room_display_name(&room).await;
+                    if !wait_for_join(&room).await {
+                        error!("Joining room '{}' didn't complete", name);
+                        return;
+                    }
                     // Immediately leave if the room is too large
                     if is_room_too_large(&room, room_size_limit).await {
-                        warn!(
-                            "Room {} has too many members, refusing to join",
-                            room.room_id()
-                        );
+                        warn!("Room '{}' has too many members, refusing to join", name);
                         if let Err(e) = room.leave().await {
                             error!("Error leaving room: {:?}", e);
                         }
                         return;
                     }
-                    info!("Successfully joined room {}", room.room_id());
+                    info!("Successfully joined room '{}'", name);
                     if let Some(callback) = callback {
                         if let Err(e) = callback(room).await {
                             error!("Error joining room: {:?}", e)
@@ -362,83 +1379,594 @@ impl Bot {
                 });
             },
         );
+        Ok(())
     }
 
-    /// Register a command that will be called for every non-command message
-    /// Useful for bots that want to act more like chatbots, having some response to every message
-    pub fn register_text_handler<F, Fut>(&self, callback: F)
+    /// Register a callback for invites from users that are not on the allow_list
+    /// The callback gets the user that sent the invite and the room they invited the bot to.
+    /// Useful for logging or alerting on unauthorized invite attempts.
+    pub fn on_rejected_invite<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
     where
-        F: FnOnce(OwnedUserId, String, Room) -> Fut + Send + 'static + Clone + Sync,
+        F: FnOnce(OwnedUserId, OwnedRoomId) -> Fut + Send + 'static + Clone + Sync,
         Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
     {
-        let client = self.client.as_ref().expect("client not initialized");
-        let allow_list = self.config.allow_list.clone();
-        let username = self.full_name();
-        let command_prefix = self.command_prefix();
+        let client = self.client()?;
+        let bot = self.clone();
+        let username = self.full_name()?;
         client.add_event_handler(
-            move |event: OriginalSyncRoomMessageEvent, room: Room| async move {
-                // Ignore messages from rooms we're not in
-                if room.state() != RoomState::Joined {
-                    return;
-                }
-                let MessageType::Text(text_content) = &event.content.msgtype else {
-                    return;
-                };
-                if !is_allowed(allow_list, event.sender.as_str(), &username) {
-                    // Sender is not on the allowlist
+            move |room_member: StrippedRoomMemberEvent, client: Client, room: Room| async move {
+                if client.user_id() != Some(&room_member.state_key)
+                    || room_member.content.membership != MembershipState::Invite
+                {
                     return;
                 }
-                let body = text_content.body.trim_start();
-                // _Ignore_ the message if it's a command
-                if is_command(&command_prefix, body) {
+                if bot
+                    .is_allowed_in_room(&room, room_member.sender.as_str(), &username)
+                    .await
+                {
                     return;
                 }
-                if let Err(e) = callback(event.sender.clone(), body.to_string(), room).await {
-                    error!("Error responding to: {}\nError: {:?}", body, e);
+                warn!(
+                    "Ignoring invite to {} from {}, who is not on the allow_list",
+                    room.room_id(),
+                    room_member.sender
+                );
+                if let Err(e) = callback(room_member.sender, room.room_id().to_owned()).await {
+                    error!("Error handling rejected invite: {:?}", e);
                 }
             },
         );
+        Ok(())
     }
 
-    /// Register a text command
-    /// This will call the callback when the command is received
-    /// Sending no help text will make the command not show up in the help
-    /// FIXME: This adds a separate handler for every command, this can be made more efficient
-    /// by storing the commands in the State struct
-    pub async fn register_text_command<F, Fut, OptString>(
-        &self,
-        command: &str,
-        args: OptString,
-        short_help: OptString,
-        callback: F,
-    ) where
-        F: FnOnce(OwnedUserId, String, Room) -> Fut + Send + 'static + Clone + Sync,
+    /// Register a callback for users knocking on a room the bot is in, asking to join it
+    /// The callback gets the user that knocked, the reason they gave and the room, and can let
+    /// them in with `Bot::accept_knock`. The allow_list doesn't apply, as the users knocking
+    /// usually aren't known yet, but knocks from ignored users are dropped.
+    pub fn on_knock<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(OwnedUserId, Option<String>, Room) -> Fut + Send + 'static + Clone + Sync,
         Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
-        OptString: Into<Option<String>>,
     {
-        {
-            // Add the command to the help list
-            let mut global_state = GLOBAL_STATE.lock().await;
-            let state = global_state.get_mut(&self.name()).unwrap();
-            let mut state = state.lock().await;
-            state.help.push(HelpText {
-                command: command.to_string(),
-                args: args.into(),
-                short: short_help.into(),
-            });
-        }
-        let client = self.client.as_ref().expect("client not initialized");
-        let allow_list = self.config.allow_list.clone();
-        let username = self.full_name();
-        let command = command.to_owned();
-        let command_prefix = self.command_prefix();
+        let client = self.client()?;
+        let bot = self.clone();
         client.add_event_handler(
-            // This handler matches pretty much every sync event, we'll use that and then filter ourselves
-            move |event: AnySyncMessageLikeEvent, room: Room| async move {
-                // Ignore messages from rooms we're not in
+            move |event: OriginalSyncRoomMemberEvent, room: Room| async move {
+                if event.content.membership != MembershipState::Knock {
+                    return;
+                }
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
+                if is_ignored(&room.client(), event.state_key.as_str()).await {
+                    return;
+                }
+                info!("{} knocked on {}", event.state_key, room.room_id());
+                let span = info_span!(
+                    "knock",
+                    room_id = %room.room_id(),
+                    sender = %event.state_key
+                );
+                if let Err(e) = callback(event.state_key, event.content.reason, room)
+                    .instrument(span)
+                    .await
+                {
+                    error!("Error handling knock: {:?}", e);
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Let a user that knocked on the room join it
+    /// Accepting a knock is inviting the user, their client then joins the room. The bot needs
+    /// the power level to invite users in the room.
+    pub async fn accept_knock(&self, room: &Room, user_id: &UserId) -> Result<(), HeadjackError> {
+        self.invite(room, user_id).await
+    }
+
+    /// Register a callback for redactions in the rooms the bot is in
+    /// The callback gets the ID of the redacted event, e.g. to undo what a command did when the
+    /// message that triggered it is deleted.
+    /// Only redactions by users on the allow_list are passed, like messages to the commands.
+    pub fn on_redaction<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(OwnedEventId, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let client = self.client()?;
+        let bot = self.clone();
+        let username = self.full_name()?;
+        client.add_event_handler(
+            move |event: OriginalSyncRoomRedactionEvent, room: Room| async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
+                    // Sender is not on the allowlist
+                    return;
+                }
+                // The redacted event moved into the content in room version 11
+                let Some(redacts) = event.redacts.or(event.content.redacts) else {
+                    return;
+                };
+                if let Err(e) = callback(redacts.clone(), room).await {
+                    error!("Error handling redaction of {}: {:?}", redacts, e);
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Register a callback for redactions of messages the bot sent, e.g. by a moderator
+    /// The callback gets the ID of the redacted event and the user that redacted it.
+    /// Only the most recent messages sent with the helpers on the Bot are tracked.
+    pub fn on_own_message_redacted<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(OwnedEventId, OwnedUserId, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let client = self.client()?;
+        let bot = self.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomRedactionEvent, room: Room| async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                let Some(redacts) = event.redacts.or(event.content.redacts) else {
+                    return;
+                };
+                let ours = {
+                    let state = bot.state.lock().await;
+                    state
+                        .sent_events
+                        .get(room.room_id())
+                        .is_some_and(|sent| sent.contains(&redacts))
+                };
+                if !ours {
+                    return;
+                }
+                warn!(
+                    "Our message {} in {} was redacted by {}",
+                    redacts,
+                    room.room_id(),
+                    event.sender
+                );
+                if let Err(e) = callback(redacts.clone(), event.sender, room).await {
+                    error!(
+                        "Error handling redaction of our message {}: {:?}",
+                        redacts, e
+                    );
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Register a callback for events that we are unable to decrypt
+    /// Useful for logging or alerting. The bot keeps retrying to decrypt them for about a
+    /// minute, and passes them to the message handlers if that works.
+    pub fn on_undecryptable<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(OwnedEventId, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let client = self.client()?;
+        client.add_event_handler(
+            move |event: OriginalSyncRoomEncryptedEvent, room: Room| async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                if let Err(e) = callback(event.event_id.clone(), room).await {
+                    error!(
+                        "Error handling undecryptable event: {} - {:?}",
+                        event.event_id, e
+                    );
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Adds a handler that logs events we are unable to decrypt and retries decrypting them
+    /// The SDK requests the missing keys from our other devices and the key backup,
+    /// so the keys may show up after a short delay. Decrypted events are passed to the message
+    /// handlers, as they would have been if they were decrypted during the sync.
+    fn log_undecryptable(&self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let bot = self.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomEncryptedEvent, room: Room, raw: RawEvent| async move {
+                if room.state() != RoomState::Joined {
+                    return;
+                }
+                warn!(
+                    "Unable to decrypt event {} from {} in room {}",
+                    event.event_id,
+                    event.sender,
+                    room.room_id()
+                );
+                let raw = Raw::from_json((*raw).to_owned());
+                tokio::spawn(async move {
+                    let mut delay = 2;
+                    while delay <= 60 {
+                        sleep(Duration::from_secs(delay)).await;
+                        if let Ok(decrypted) = room.decrypt_event(&raw).await {
+                            info!("Decrypted event {} after retrying", event.event_id);
+                            match decrypted.event.cast::<AnySyncTimelineEvent>().deserialize() {
+                                Ok(decrypted) => bot.replay_event(decrypted, &room).await,
+                                Err(e) => {
+                                    warn!("Decrypted event {} is invalid: {:?}", event.event_id, e)
+                                }
+                            }
+                            return;
+                        }
+                        delay *= 2;
+                    }
+                    error!("Giving up on decrypting event {}", event.event_id);
+                });
+            },
+        );
+        Ok(())
+    }
+
+    /// Register a callback for a type of state event, e.g. topic, name or power level changes
+    /// The type of event is selected by the content type, e.g. `RoomTopicEventContent`.
+    /// The previous content, if any, is available in `event.unsigned.prev_content`.
+    /// Only called for rooms we're in, and redacted state events are ignored.
+    pub fn on_state_event<C, F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        C: StaticEventContent + StaticStateEventContent + Send + 'static,
+        OriginalSyncStateEvent<C>: DeserializeOwned + Send,
+        F: FnOnce(OriginalSyncStateEvent<C>, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let client = self.client()?;
+        client.add_event_handler(
+            move |event: OriginalSyncStateEvent<C>, room: Room| async move {
+                // Ignore events from rooms we're not in
                 if room.state() != RoomState::Joined {
                     return;
                 }
+                let event_id = event.event_id.clone();
+                if let Err(e) = callback(event, room).await {
+                    error!(
+                        "Error handling {} state event: {} - {:?}",
+                        C::TYPE,
+                        event_id,
+                        e
+                    );
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Register a command that will be called for every non-command message
+    /// Useful for bots that want to act more like chatbots, having some response to every message
+    /// In rooms larger than `mention_only_room_size`, it's only called when the bot is mentioned.
+    /// Only text messages are handled, notices only with `handle_notices`.
+    pub fn register_text_handler<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(Message, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let bot = self.clone();
+        let username = self.full_name()?;
+        self.add_replayable_handler(
+            replay::room_message,
+            move |event: OriginalSyncRoomMessageEvent, room: Room| async move {
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
+                let (mention_only_room_size, log_message_bodies, debounce) = {
+                    let config = bot.config();
+                    (
+                        config.mention_only_room_size,
+                        config.log_message_bodies.unwrap_or(false),
+                        config.text_debounce,
+                    )
+                };
+                // Only look at text messages, and notices if enabled
+                if !bot.handles_msgtype(&event.content.msgtype) {
+                    return;
+                }
+                if event.sender == username {
+                    // Keep our own messages in the conversation, but don't respond to them
+                    bot.record_message(&room, &event, strip_reply_fallback(&event))
+                        .await;
+                    return;
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
+                    // Sender is not on the allowlist
+                    return;
+                }
+                let body = strip_reply_fallback(&event).trim_start();
+                // _Ignore_ the message if it's a command
+                if let Some((command, _)) = bot.split_command(body) {
+                    // Mentions of the bot that aren't commands are regular messages
+                    let by_mention =
+                        bot.has_mention_prefix() || bot.split_prefix_command(body).is_none();
+                    if !by_mention
+                        || bot
+                            .state
+                            .lock()
+                            .await
+                            .help
+                            .iter()
+                            .any(|h| h.command == command)
+                    {
+                        return;
+                    }
+                }
+                // In busy rooms, only respond when we're mentioned
+                if let Some(limit) = mention_only_room_size {
+                    if room.joined_members_count() > limit && !is_mentioned(&event, &username) {
+                        return;
+                    }
+                }
+                bot.record_message(&room, &event, body).await;
+                let Some(window) = debounce else {
+                    let message = Message::new(event.clone(), body, "");
+                    call_text_handler(callback, message, room, log_message_bodies).await;
+                    return;
+                };
+                let key = (room.room_id().to_owned(), event.sender.clone());
+                {
+                    let mut state = bot.state.lock().await;
+                    if let Some(pending) = state.pending_texts.get_mut(&key) {
+                        // Already waiting for more messages from this sender
+                        pending.bodies.push(body.to_string());
+                        pending.event = event.clone();
+                        pending.received = Instant::now();
+                        return;
+                    }
+                    state.pending_texts.insert(
+                        key.clone(),
+                        PendingText {
+                            bodies: vec![body.to_string()],
+                            event: event.clone(),
+                            received: Instant::now(),
+                        },
+                    );
+                }
+                // Wait in a separate task, the sync that delivers the next messages waits for
+                // the handlers to finish
+                tokio::spawn(async move {
+                    let pending = loop {
+                        let received = match bot.state.lock().await.pending_texts.get(&key) {
+                            Some(pending) => pending.received,
+                            None => return,
+                        };
+                        let elapsed = received.elapsed();
+                        if elapsed < window {
+                            sleep(window - elapsed).await;
+                            continue;
+                        }
+                        let mut state = bot.state.lock().await;
+                        if state.pending_texts.get(&key).map(|p| p.received) == Some(received) {
+                            if let Some(pending) = state.pending_texts.remove(&key) {
+                                break pending;
+                            }
+                        }
+                    };
+                    let body = pending.bodies.join("\n");
+                    let message = Message::new(pending.event, &body, "");
+                    call_text_handler(callback, message, room, log_message_bodies).await;
+                });
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Record a message in the conversation of the room, logging any error
+    /// Edits, e.g. of the messages streamed by `ResponseStream`, aren't new turns, so they're
+    /// skipped.
+    async fn record_message(&self, room: &Room, event: &OriginalSyncRoomMessageEvent, body: &str) {
+        if matches!(event.content.relates_to, Some(Relation::Replacement(_))) {
+            return;
+        }
+        let turn = ChatTurn {
+            sender: event.sender.clone(),
+            body: body.to_string(),
+            ts: event.origin_server_ts,
+        };
+        if let Err(e) = self.record_turn(room.room_id(), turn).await {
+            error!(
+                "Error recording the conversation in {}: {:?}",
+                room.room_id(),
+                e
+            );
+        }
+    }
+
+    /// Register a text handler with a context that is passed to the callback
+    /// The context is cloned for every invocation, see `register_text_command_with_ctx`.
+    pub fn register_text_handler_with_ctx<C, F, Fut>(
+        &self,
+        ctx: C,
+        callback: F,
+    ) -> Result<(), HeadjackError>
+    where
+        C: Clone + Send + Sync + 'static,
+        F: FnOnce(C, Message, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        self.register_text_handler(move |message, room| callback(ctx, message, room))
+    }
+
+    /// Register a handler for every message-like event in the rooms the bot is in
+    /// Unlike the other handlers it gets every event type and msgtype, e.g. for logging or
+    /// auditing, and is called in addition to them. Only the room and allow_list checks apply,
+    /// so the bot's own events are skipped.
+    pub fn register_any_message_handler<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(AnySyncMessageLikeEvent, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let bot = self.clone();
+        let username = self.full_name()?;
+        self.add_replayable_handler(
+            replay::message_like,
+            move |event: AnySyncMessageLikeEvent, room: Room| async move {
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender().as_str(), &username)
+                    .await
+                {
+                    // Sender is not on the allowlist
+                    return;
+                }
+                let event_id = event.event_id().to_owned();
+                let span = info_span!(
+                    "any_message",
+                    event_type = %event.event_type(),
+                    room_id = %room.room_id(),
+                    sender = %event.sender()
+                );
+                if let Err(e) = callback(event, room).instrument(span).await {
+                    error!("Error handling event {}: {:?}", event_id, e);
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Register a command that is triggered by reacting to a message with the given key
+    /// The key is usually an emoji, e.g. "🔖".
+    /// The callback gets the user that reacted and the ID of the event they reacted to.
+    pub fn register_reaction_command<F, Fut>(
+        &self,
+        key: &str,
+        callback: F,
+    ) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(OwnedUserId, OwnedEventId, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let bot = self.clone();
+        let username = self.full_name()?;
+        let key = key.to_owned();
+        self.add_replayable_handler(
+            replay::reaction,
+            move |event: OriginalSyncReactionEvent, room: Room| async move {
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
+                if event.content.relates_to.key != key {
+                    return;
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
+                    // Sender is not on the allowlist
+                    return;
+                }
+                let target = event.content.relates_to.event_id;
+                let span = info_span!(
+                    "reaction_command",
+                    key = %key,
+                    room_id = %room.room_id(),
+                    sender = %event.sender
+                );
+                if let Err(e) = callback(event.sender, target, room).instrument(span).await {
+                    error!("Error running reaction command: {} - {:?}", key, e);
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Register a handler for locations shared in the rooms the bot is in
+    /// The location is in `Message::geo_uri`, and the body is its description.
+    pub fn register_location_handler<F, Fut>(&self, callback: F) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(Message, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let bot = self.clone();
+        let username = self.full_name()?;
+        self.add_replayable_handler(
+            replay::room_message,
+            move |event: OriginalSyncRoomMessageEvent, room: Room| async move {
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
+                let MessageType::Location(location) = &event.content.msgtype else {
+                    return;
+                };
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
+                    // Sender is not on the allowlist
+                    return;
+                }
+                let message = Message::new(event.clone(), &location.body, "");
+                let span = info_span!(
+                    "location_handler",
+                    room_id = %room.room_id(),
+                    sender = %event.sender
+                );
+                if let Err(e) = callback(message, room).instrument(span).await {
+                    error!("Error handling location {}: {:?}", event.event_id, e);
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Register a text command
+    /// This will call the callback when the command is received
+    /// Sending no help text will make the command not show up in the help
+    /// FIXME: This adds a separate handler for every command, this can be made more efficient
+    /// by storing the commands in the State struct
+    pub async fn register_text_command<F, Fut, OptString>(
+        &self,
+        command: &str,
+        args: OptString,
+        short_help: OptString,
+        callback: F,
+    ) -> Result<(), HeadjackError>
+    where
+        F: FnOnce(Message, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+        OptString: Into<Option<String>>,
+    {
+        {
+            // Add the command to the help list
+            let mut state = self.state.lock().await;
+            state.help.push(HelpText {
+                command: command.to_string(),
+                args: args.into(),
+                short: short_help.into(),
+            });
+        }
+        let bot = self.clone();
+        let username = self.full_name()?;
+        let command = command.to_owned();
+        self.add_replayable_handler(
+            replay::message_like,
+            // This handler matches pretty much every sync event, we'll use that and then filter ourselves
+            move |event: AnySyncMessageLikeEvent, room: Room| async move {
+                // Ignore rooms we're not in, or that lack the required tag
+                if !bot.handles_room(&room).await {
+                    return;
+                }
                 // Ignore non-message events
                 let AnySyncMessageLikeEvent::RoomMessage(event) = event else {
                     return;
@@ -447,74 +1975,492 @@ impl Bot {
                 let Some(event) = event.as_original() else {
                     return;
                 };
-                // Only look at text messages
-                let MessageType::Text(_) = event.content.msgtype else {
+                // Only look at text messages, and notices if enabled
+                if !bot.handles_msgtype(&event.content.msgtype) {
                     return;
-                };
-                let text_content = event.content.body();
-                if !is_allowed(allow_list, event.sender.as_str(), &username) {
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
                     // Sender is not on the allowlist
                     return;
                 }
-                let body = text_content.trim_start();
-                if let Some(input_command) = get_command(&command_prefix, body) {
+                let body = strip_reply_fallback(event).trim_start();
+                if let Some((input_command, args)) = bot.split_command(body) {
                     if input_command == command {
                         // Call the callback
-                        if let Err(e) = callback(event.sender.clone(), body.to_string(), room).await
-                        {
-                            error!("Error running command: {} - {:?}", command, e);
+                        let message = Message::new(event.clone(), body, args);
+                        let span = info_span!(
+                            "command",
+                            command = %command,
+                            room_id = %room.room_id(),
+                            sender = %event.sender
+                        );
+                        if !bot.starts_burst(&room, &message).await {
+                            return;
+                        }
+                        // Held until the command finishes
+                        let _permit = match &bot.command_permits {
+                            Some(permits) => match permits.clone().try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    warn!("Too many commands running, not running {}", command);
+                                    bot.reply_text(&room, &message, bot.strings().busy).await;
+                                    return;
+                                }
+                            },
+                            None => None,
+                        };
+                        let command_timeout = bot.config().command_timeout;
+                        let Some(timeout) = command_timeout else {
+                            if let Err(e) = callback(message, room).instrument(span).await {
+                                error!("Error running command: {} - {:?}", command, e);
+                            }
+                            return;
+                        };
+                        // Dropping the future on timeout cancels the command
+                        let future = callback(message.clone(), room.clone()).instrument(span);
+                        match tokio::time::timeout(timeout, future).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => error!("Error running command: {} - {:?}", command, e),
+                            Err(_) => {
+                                warn!("Command {} timed out after {:?}", command, timeout);
+                                let response = bot.strings().command_timed_out;
+                                bot.reply_text(&room, &message, response).await;
+                            }
                         }
                     }
                 }
             },
-        );
+        )?;
+        Ok(())
+    }
+
+    /// Register a text command with a context that is passed to the callback
+    /// The context is cloned for every invocation, so it's useful for sharing state like
+    /// an HTTP client or a database pool without wrapping the callback yourself.
+    pub async fn register_text_command_with_ctx<C, F, Fut, OptString>(
+        &self,
+        ctx: C,
+        command: &str,
+        args: OptString,
+        short_help: OptString,
+        callback: F,
+    ) -> Result<(), HeadjackError>
+    where
+        C: Clone + Send + Sync + 'static,
+        F: FnOnce(C, Message, Room) -> Fut + Send + 'static + Clone + Sync,
+        Fut: std::future::Future<Output = Result<(), ()>> + Send + 'static,
+        OptString: Into<Option<String>>,
+    {
+        self.register_text_command(command, args, short_help, move |message, room| {
+            callback(ctx, message, room)
+        })
+        .await
     }
 
     /// Run the bot continuously
     /// This function takes ownership of the bot, we'll be moving data out of it for use in the function closures
-    pub async fn run(&self) -> anyhow::Result<()> {
-        self.register_help_command().await;
-        let client = self.client.as_ref().expect("client not initialized");
+    pub async fn run(&self) -> Result<(), HeadjackError> {
+        self.run_until(std::future::pending()).await
+    }
 
-        let filter = FilterDefinition::with_lazy_loading();
-        let mut sync_settings = SyncSettings::default().filter(filter.into());
+    /// Run the bot until the shutdown future completes, e.g. on Ctrl-C
+    /// Useful for running the bot alongside other work, like an HTTP server. On shutdown the sync
+    /// in progress is abandoned and the sync token of the last finished sync is persisted, so the
+    /// events of an unfinished sync are handled again on the next run rather than lost.
+    pub async fn run_until<F>(&self, shutdown: F) -> Result<(), HeadjackError>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        {
+            // Track the start time for the uptime
+            self.state.lock().await.start_time = Some(Instant::now());
+        }
+        self.log_undecryptable()?;
+        #[cfg(feature = "macros")]
+        self.register_declared_commands().await?;
+        if self.config().builtin_help.unwrap_or(true) {
+            self.register_help_command().await?;
+        }
+        if self.config().ping_command.unwrap_or(false) {
+            self.register_ping_command().await?;
+        }
+        if self.config().rooms_command.unwrap_or(false) {
+            self.register_rooms_command().await?;
+        }
+        if self.config().whoami_command.unwrap_or(false) {
+            self.register_whoami_command().await?;
+        }
+        if self.config().ignore_commands.unwrap_or(false) {
+            self.register_ignore_commands().await?;
+        }
+        if self.config().purge_command.unwrap_or(false) {
+            self.register_purge_command().await?;
+        }
+        self.register_pagination_handler()?;
+        self.replay_backfill().await?;
+        let allow_list_room = self.config().allow_list_room.clone();
+        if let Some(room_id) = allow_list_room {
+            self.watch_allow_list_room(&room_id)?;
+        }
+        let client = self.client()?;
+
+        #[cfg(feature = "sliding-sync")]
+        if self.config().sliding_sync.unwrap_or(false) {
+            return tokio::select! {
+                result = self.run_sliding_sync().instrument(info_span!("sync")) => result,
+                () = shutdown => {
+                    info!("Shutting down");
+                    Ok(())
+                }
+            };
+        }
+
+        let mut sync_settings = SyncSettings::default().filter(self.sync_filter().into());
 
         // If we've already synced through a certain point, we'll sync the latest.
         if let Some(sync_token) = &self.sync_token {
             sync_settings = sync_settings.token(sync_token);
         }
-        // This loops until we kill the program or an error happens.
-        client
+        let persist_interval = self.config().sync_token_persist_interval;
+        let last_persist = Mutex::new(Instant::now());
+        let last_persist = &last_persist;
+        let persist_failures = Mutex::new(0);
+        let persist_failures = &persist_failures;
+        // This loops until we're shut down or a fatal error happens.
+        let sync = client
             .sync_with_result_callback(sync_settings, |sync_result| async move {
-                let response = sync_result?;
+                let response = match sync_result {
+                    Ok(response) => response,
+                    Err(err) if is_fatal_sync_error(&err) => {
+                        trace!("Sync error: {err:?}");
+                        error!("Fatal sync error, stopping: {}", describe_sync_error(&err));
+                        return Err(err);
+                    }
+                    Err(err) => {
+                        // The SDK waits between syncs, so this won't busy loop
+                        trace!("Sync error: {err:?}");
+                        warn!("Sync failed, retrying: {}", describe_sync_error(&err));
+                        return Ok(LoopCtrl::Continue);
+                    }
+                };
 
-                // We persist the token each time to be able to restore our session
-                self.persist_sync_token(response.next_batch)
-                    .await
-                    .map_err(|err| Error::UnknownError(err.into()))?;
+                if !self.initial_sync_complete.swap(true, Ordering::Relaxed) {
+                    info!("Initial sync done, in {} rooms", response.rooms.join.len());
+                }
+
+                // Keep the latest token in memory so that it can always be flushed
+                {
+                    self.state.lock().await.pending_sync_token = Some(response.next_batch);
+                }
+
+                // We persist the token periodically to be able to restore our session
+                let mut last_persist = last_persist.lock().await;
+                if persist_interval.map_or(true, |interval| last_persist.elapsed() >= interval) {
+                    // A failed write is retried on the next sync, the token is kept in memory
+                    let mut persist_failures = persist_failures.lock().await;
+                    match self.flush_sync_token().await {
+                        Ok(()) => {
+                            *persist_failures = 0;
+                            *last_persist = Instant::now();
+                        }
+                        Err(err) if *persist_failures + 1 >= MAX_PERSIST_FAILURES => {
+                            error!("Failed to persist the sync token {MAX_PERSIST_FAILURES} times in a row, stopping: {err}");
+                            return Err(Error::UnknownError(err.into()));
+                        }
+                        Err(err) => {
+                            *persist_failures += 1;
+                            warn!("Failed to persist the sync token: {err}");
+                        }
+                    }
+                }
 
                 Ok(LoopCtrl::Continue)
             })
-            .await?;
+            .instrument(info_span!("sync"));
+        let result = tokio::select! {
+            result = sync => result,
+            () = shutdown => {
+                info!("Shutting down");
+                Ok(())
+            }
+        };
+
+        // Don't lose the latest token if persisting was debounced
+        // A failed write is only logged, so it doesn't hide why the sync stopped.
+        if let Err(err) = self.flush_sync_token().await {
+            error!("Failed to persist the sync token: {err}");
+        }
+        if let Err(err) = result {
+            return Err(self.sync_error(err).await);
+        }
+
+        Ok(())
+    }
+
+    /// Convert an error that stopped syncing, renewing the session after a soft logout
+    async fn sync_error(&self, error: Error) -> HeadjackError {
+        let Some(ErrorKind::UnknownToken { soft_logout }) = error.client_api_error_kind() else {
+            return error.into();
+        };
+        let soft_logout = *soft_logout;
+        error!("The homeserver invalidated the access token (soft logout: {soft_logout})");
+        if soft_logout {
+            match self.renew_session().await {
+                Ok(true) => info!("Logged in again on the same device, restart the bot to resume"),
+                Ok(false) => {}
+                Err(e) => error!("Error logging in again: {e}"),
+            }
+        }
+        HeadjackError::TokenInvalidated { soft_logout }
+    }
+
+    /// Log in again on the same device, and save the new access token in the session file
+    /// The SDK can't replace the token of a running client, so it's only used after a restart.
+    /// Returns false if there is no password or no session file to renew.
+    async fn renew_session(&self) -> Result<bool, HeadjackError> {
+        let login = self.config().login.clone();
+        let Some(password) = login.password else {
+            return Ok(false);
+        };
+        let Some(device_id) = self
+            .state
+            .lock()
+            .await
+            .session
+            .as_ref()
+            .map(|session| session.user_session.meta.device_id.clone())
+        else {
+            return Ok(false);
+        };
+        let mut request = login::v3::Request::new(LoginInfo::Password(Password::new(
+            UserIdentifier::UserIdOrLocalpart(login.username),
+            password,
+        )));
+        request.device_id = Some(device_id);
+        let response = self
+            .client()?
+            .send(request, None)
+            .await
+            .map_err(|e| HeadjackError::login(e.into()))?;
+        let serialized_session = {
+            let mut state = self.state.lock().await;
+            let Some(full_session) = state.session.as_mut() else {
+                return Ok(false);
+            };
+            full_session.user_session.tokens = MatrixSessionTokens {
+                access_token: response.access_token,
+                refresh_token: response.refresh_token,
+            };
+            serde_json::to_string(full_session)?
+        };
+        write_atomic(&self.session_file(), serialized_session).await?;
+        Ok(true)
+    }
+
+    /// Check if the first sync with the homeserver has finished
+    /// Until then the bot may not know all its rooms, and the initial sync can take minutes for
+    /// accounts in many rooms.
+    pub fn is_initial_sync_complete(&self) -> bool {
+        self.initial_sync_complete.load(Ordering::Relaxed)
+    }
+
+    /// Get the sync token the bot will start syncing from
+    pub fn sync_token(&self) -> Option<&str> {
+        self.sync_token.as_deref()
+    }
+
+    /// Reset the sync token, both in memory and on disk
+    /// The next `sync()` or `run()` will do a full sync from scratch.
+    pub async fn reset_sync_token(&mut self) -> Result<(), HeadjackError> {
+        self.sync_token = None;
+        let serialized_session = {
+            let mut state = self.state.lock().await;
+            state.pending_sync_token = None;
+            let Some(full_session) = state.session.as_mut() else {
+                return Ok(());
+            };
+            full_session.sync_token = None;
+            serde_json::to_string(full_session)?
+        };
+        write_atomic(&self.session_file(), serialized_session).await?;
+        info!("Sync token reset, the next sync will start from scratch");
+        Ok(())
+    }
+
+    /// Persist the latest sync token to disk, if it hasn't been persisted yet
+    /// If `sync_token_persist_interval` is set, call this before shutting down to make sure
+    /// the latest sync token is saved.
+    pub async fn flush_sync_token(&self) -> Result<(), HeadjackError> {
+        let pending = {
+            let mut state = self.state.lock().await;
+            state.pending_sync_token.take()
+        };
+        if let Some(sync_token) = pending {
+            if let Err(err) = self.persist_sync_token(sync_token.clone()).await {
+                // Keep it for the next flush, unless a newer token arrived meanwhile
+                self.state
+                    .lock()
+                    .await
+                    .pending_sync_token
+                    .get_or_insert(sync_token);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the sync_token to the session file
+    /// Uses the in-memory copy of the session, and only writes if the token changed. The copy
+    /// is only updated once the write succeeded, so a failed write is retried with the same token.
+    async fn persist_sync_token(&self, sync_token: String) -> Result<(), HeadjackError> {
+        let serialized_session = {
+            let mut state = self.state.lock().await;
+            // There is no session to persist with the in-memory store
+            let Some(full_session) = state.session.as_mut() else {
+                return Ok(());
+            };
+            if full_session.sync_token.as_ref() == Some(&sync_token) {
+                return Ok(());
+            }
+            let persisted = full_session.sync_token.replace(sync_token.clone());
+            let serialized_session = serde_json::to_string(full_session);
+            full_session.sync_token = persisted;
+            serialized_session?
+        };
+        write_atomic(&self.session_file(), serialized_session).await?;
+        if let Some(full_session) = self.state.lock().await.session.as_mut() {
+            full_session.sync_token = Some(sync_token);
+        }
 
         Ok(())
     }
 
-    async fn persist_sync_token(&self, sync_token: String) -> anyhow::Result<()> {
-        let serialized_session = fs::read_to_string(self.session_file().clone()).await?;
-        let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+    /// Check if the user is on the global allow_list
+    /// The same check the commands and handlers do, without the room's allow_list override or
+    /// the ignored users, so callbacks can use it for their own permission checks.
+    pub fn is_sender_allowed(&self, sender: &UserId) -> bool {
+        let username = self.user_id().map(|id| id.to_string()).unwrap_or_default();
+        is_allowed(&self.allow_list_regex(), sender.as_str(), &username)
+    }
+
+    /// Check if the user is allowed in the room
+    /// The same check the commands and handlers do, with the room's allow_list override and the
+    /// ignored users.
+    pub async fn is_sender_allowed_in_room(&self, room: &Room, sender: &UserId) -> bool {
+        let username = self.user_id().map(|id| id.to_string()).unwrap_or_default();
+        self.is_allowed_in_room(room, sender.as_str(), &username)
+            .await
+    }
+
+    /// Verify if the sender is allowed in the given room
+    /// Uses the room's allow_list override if one is set, otherwise the global allow_list.
+    /// Ignored users are never allowed, see `Bot::ignore_user`.
+    async fn is_allowed_in_room(&self, room: &Room, sender: &str, username: &str) -> bool {
+        if is_ignored(&room.client(), sender).await {
+            return false;
+        }
+        match self.compiled_room_allow_list(room).await {
+            Some(room_allow_list) => is_allowed(&room_allow_list, sender, username),
+            None => is_allowed(&self.allow_list_regex(), sender, username),
+        }
+    }
+
+    /// Get the compiled allow_list override of the room, if it has one
+    /// Compiled once and cached until the room's account data changes. An invalid override is
+    /// an empty set, so no one is allowed in the room.
+    async fn compiled_room_allow_list(&self, room: &Room) -> Option<Arc<RegexSet>> {
+        if let Some(cached) = self
+            .room_allow_lists
+            .read()
+            .expect("room allow_lists lock poisoned")
+            .get(room.room_id())
+        {
+            return cached.clone();
+        }
+        let room_allow_list =
+            get_room_allow_list(room)
+                .await
+                .map(|allow_list| match RegexSet::new(&allow_list) {
+                    Ok(allow_list) => Arc::new(allow_list),
+                    Err(e) => {
+                        error!("Invalid allow_list for room {}: {:?}", room.room_id(), e);
+                        Arc::new(RegexSet::empty())
+                    }
+                });
+        self.room_allow_lists
+            .write()
+            .expect("room allow_lists lock poisoned")
+            .insert(room.room_id().to_owned(), room_allow_list.clone());
+        room_allow_list
+    }
+
+    /// Drop the cached allow_list override of a room when its account data changes
+    pub(crate) fn watch_room_allow_lists(&self) -> Result<(), HeadjackError> {
+        let room_allow_lists = self.room_allow_lists.clone();
+        self.client()?
+            .add_event_handler(move |event: Raw<AnyRoomAccountDataEvent>, room: Room| {
+                let room_allow_lists = room_allow_lists.clone();
+                async move {
+                    let event_type = event.get_field::<String>("type").ok().flatten();
+                    if event_type.as_deref() == Some(ROOM_ALLOW_LIST_TYPE) {
+                        room_allow_lists
+                            .write()
+                            .expect("room allow_lists lock poisoned")
+                            .remove(room.room_id());
+                    }
+                }
+            });
+        Ok(())
+    }
+
+    /// Get the compiled allow_list, with one regex per entry
+    /// `RegexSet::matches` tells which of the entries a user matched. The allow_list is compiled
+    /// once, and again by `reload_config`. If it's invalid the set is empty, so no one matches.
+    pub fn allow_list_regex(&self) -> Arc<RegexSet> {
+        match &*self.allow_list.read().expect("allow_list lock poisoned") {
+            Ok(allow_list) => allow_list.clone(),
+            Err(_) => Arc::new(RegexSet::empty()),
+        }
+    }
 
-        full_session.sync_token = Some(sync_token);
-        let serialized_session = serde_json::to_string(&full_session)?;
-        fs::write(self.session_file().clone(), serialized_session).await?;
+    /// Get the allow_list override for a room, if one is set
+    pub async fn room_allow_list(&self, room: &Room) -> Option<Vec<String>> {
+        get_room_allow_list(room).await
+    }
 
+    /// Set the allow_list override for a room
+    /// The room's allow_list replaces the global allow_list for messages and invites in that room.
+    /// Setting None removes the override so that the global allow_list applies again.
+    pub async fn set_room_allow_list(
+        &self,
+        room: &Room,
+        allow_list: Option<Vec<String>>,
+    ) -> Result<(), HeadjackError> {
+        // Validate the regexes before storing them
+        for allow in allow_list.iter().flatten() {
+            Regex::new(allow)?;
+        }
+        let client = self.client()?;
+        let user_id = self.user_id().ok_or(HeadjackError::NotLoggedIn)?;
+        let content = serde_json::value::to_raw_value(&RoomAllowList { allow_list })?;
+        let request = set_room_account_data::v3::Request::new_raw(
+            user_id,
+            room.room_id().to_owned(),
+            ROOM_ALLOW_LIST_TYPE.into(),
+            Raw::from_json(content),
+        );
+        client.send(request, None).await?;
         Ok(())
     }
 
     /// Get the state directory for the bot
     pub fn state_dir(&self) -> PathBuf {
-        if let Some(state_dir) = &self.config.state_dir {
-            PathBuf::from(expand_tilde(state_dir))
+        let state_dir = self.config().state_dir.clone();
+        if let Some(state_dir) = state_dir {
+            PathBuf::from(expand_tilde(&state_dir))
         } else {
             dirs::state_dir()
                 .expect("no state_dir directory found")
@@ -524,64 +2470,476 @@ impl Bot {
 
     /// Get the name of the bot
     pub fn name(&self) -> String {
-        self.config
+        let config = self.config();
+        config
             .name
             .clone()
-            .unwrap_or_else(|| self.config.login.username.clone())
+            .unwrap_or_else(|| config.login.username.clone())
+    }
+
+    /// Get the full name of the bot, i.e. its user ID
+    /// Fails with `HeadjackError::NotLoggedIn` if the bot isn't logged in yet.
+    pub fn full_name(&self) -> Result<String, HeadjackError> {
+        self.user_id()
+            .map(|user_id| user_id.to_string())
+            .ok_or(HeadjackError::NotLoggedIn)
+    }
+
+    /// Get the current configuration
+    /// Don't hold on to the guard across an await, it blocks reloading the config.
+    fn config(&self) -> RwLockReadGuard<'_, BotConfig> {
+        self.config.read().expect("config lock poisoned")
+    }
+
+    /// Replace the configuration while the bot is running
+    /// Only these fields take effect immediately: `allow_list`, `command_prefix`,
+    /// `mention_commands`, `prefix_config`, `room_size_limit`, `mention_only_room_size`,
+    /// `admin_power_level`, `read_only`, `log_message_bodies`, `require_encryption`,
+    /// `ordered_sends`, `multi_bot`, `command_timeout`, `command_burst_window`,
+    /// `required_room_tag`, `handle_notices`, `max_session_turns` and `text_debounce`.
+    /// The other fields, like `login`, `name`, `state_dir` and the builtin commands, are only
+    /// read at startup and are left unchanged. An invalid allow_list is logged, and the old one
+    /// is kept, and so is the old `command_prefix` if the new one is empty. With an
+    /// `allow_list_room` the allow_list is only changed from the room.
+    pub fn reload_config(&self, new_config: BotConfig) {
+        let has_allow_list_room = self.config().allow_list_room.is_some();
+        if !has_allow_list_room {
+            if let Err(e) = self.replace_allow_list(new_config.allow_list) {
+                error!("Invalid allow_list, keeping the old one: {}", e);
+            }
+        }
+        let mut config = self.config.write().expect("config lock poisoned");
+        match check_command_prefix(new_config.command_prefix.as_deref()) {
+            Ok(()) => config.command_prefix = new_config.command_prefix,
+            Err(e) => error!("Keeping the old command_prefix: {}", e),
+        }
+        config.mention_commands = new_config.mention_commands;
+        config.prefix_config = new_config.prefix_config;
+        config.room_size_limit = new_config.room_size_limit;
+        config.mention_only_room_size = new_config.mention_only_room_size;
+        config.admin_power_level = new_config.admin_power_level;
+        config.read_only = new_config.read_only;
+        config.log_message_bodies = new_config.log_message_bodies;
+        config.require_encryption = new_config.require_encryption;
+        config.ordered_sends = new_config.ordered_sends;
+        config.multi_bot = new_config.multi_bot;
+        config.command_timeout = new_config.command_timeout;
+        config.command_burst_window = new_config.command_burst_window;
+        config.required_room_tag = new_config.required_room_tag;
+        config.handle_notices = new_config.handle_notices;
+        config.max_session_turns = new_config.max_session_turns;
+        config.text_debounce = new_config.text_debounce;
+        info!("Configuration reloaded");
+    }
+
+    /// Replace the allow_list, if all its entries are valid regexes
+    fn replace_allow_list(&self, allow_list: Vec<String>) -> Result<(), regex::Error> {
+        let regex = Arc::new(RegexSet::new(&allow_list)?);
+        // Locked in the same order as everywhere else, the config first
+        let mut config = self.config.write().expect("config lock poisoned");
+        *self.allow_list.write().expect("allow_list lock poisoned") = Ok(regex);
+        config.allow_list = allow_list;
+        Ok(())
+    }
+
+    /// Get the user ID of the bot
+    /// Returns None if the bot isn't logged in yet.
+    pub fn user_id(&self) -> Option<OwnedUserId> {
+        self.client.as_ref()?.user_id().map(ToOwned::to_owned)
+    }
+
+    /// Get the device ID of the bot
+    /// Returns None if the bot isn't logged in yet.
+    pub fn device_id(&self) -> Option<OwnedDeviceId> {
+        self.client.as_ref()?.device_id().map(ToOwned::to_owned)
+    }
+
+    /// Get the URL of the homeserver the bot is connected to
+    /// Returns None if the bot isn't logged in yet.
+    pub fn homeserver(&self) -> Option<Url> {
+        Some(self.client.as_ref()?.homeserver())
     }
 
-    /// Get the full name of the bot
-    pub fn full_name(&self) -> String {
-        self.client().user_id().unwrap().to_string()
+    /// Get the client used by the bot
+    /// Fails with `HeadjackError::NotLoggedIn` if the bot isn't logged in yet.
+    pub fn client(&self) -> Result<&Client, HeadjackError> {
+        self.client.as_ref().ok_or(HeadjackError::NotLoggedIn)
     }
 
     /// Get the client used by the bot
-    pub fn client(&self) -> &Client {
-        self.client.as_ref().expect("client not initialized")
+    /// Returns None if the bot isn't logged in yet.
+    pub fn try_client(&self) -> Option<&Client> {
+        self.client.as_ref()
+    }
+
+    /// Get the name of the builtin help command
+    pub fn help_command(&self) -> String {
+        self.config()
+            .help_command
+            .clone()
+            .unwrap_or_else(|| "help".to_string())
+    }
+
+    /// Get the user facing strings of the builtin commands
+    pub fn strings(&self) -> Strings {
+        self.config().strings.clone().unwrap_or_default()
     }
 
     /// Get the command prefix for the bot
+    /// With `MENTION_PREFIX` this is the user ID of the bot, as that's how it's addressed.
     pub fn command_prefix(&self) -> String {
-        let prefix = self
-            .config
-            .command_prefix
-            .clone()
-            .unwrap_or_else(|| format!("!{} ", self.name()));
+        if self.has_mention_prefix() {
+            return format!(
+                "{} ",
+                self.user_id().map_or(self.name(), |id| id.to_string())
+            );
+        }
+        let (prefix, multi_bot) = {
+            let config = self.config();
+            (
+                config.command_prefix.clone(),
+                config.multi_bot.unwrap_or(false),
+            )
+        };
+        let Some(prefix) = prefix else {
+            return format!("!{} ", self.name());
+        };
+        // In multi-bot mode the name follows the prefix, so only the addressed bot responds
+        if multi_bot {
+            let separator = if prefix.chars().count() == 1 || prefix.ends_with(' ') {
+                ""
+            } else {
+                " "
+            };
+            return format!("{}{}{} ", prefix, separator, self.name());
+        }
         // If the prefix is 1 character, we'll return it as it. If it's more than 1 character, we'll ensure it ends with a space
-        if prefix.len() == 1 || prefix.ends_with(' ') {
+        if prefix.chars().count() == 1 || prefix.ends_with(' ') {
             prefix
         } else {
             format!("{} ", prefix)
         }
     }
+
+    /// Whether the bot is addressed with a mention instead of a prefix
+    fn has_mention_prefix(&self) -> bool {
+        self.config().command_prefix.as_deref() == Some(MENTION_PREFIX)
+    }
+
+    /// Whether messages in the room are handled
+    /// The bot must have joined the room, and it must have the `required_room_tag` if set.
+    async fn handles_room(&self, room: &Room) -> bool {
+        if room.state() != RoomState::Joined {
+            return false;
+        }
+        let Some((namespace, tag)) = self.config().required_room_tag.clone() else {
+            return true;
+        };
+        match get_tags(room).await {
+            Ok(tags) => tags.contains(&tag_name(&namespace, &tag)),
+            Err(e) => {
+                error!("Error reading the tags of {}: {:?}", room.room_id(), e);
+                false
+            }
+        }
+    }
+
+    /// Whether messages of this type are passed to the commands and the text handler
+    /// Text messages always are, notices only with `handle_notices`.
+    fn handles_msgtype(&self, msgtype: &MessageType) -> bool {
+        match msgtype {
+            MessageType::Text(_) => true,
+            MessageType::Notice(_) => self.config().handle_notices.unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Reply to a message with one of the `strings`, logging any error
+    /// Empty strings aren't sent, so that replies can be turned off.
+    async fn reply_text(&self, room: &Room, message: &Message, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        let content = RoomMessageEventContent::text_plain(text);
+        if let Err(e) = self.reply(room, message, content).await {
+            error!("Error replying to {}: {:?}", message.event_id(), e);
+        }
+    }
+
+    /// Whether the command may run, as the first of a burst, see `command_burst_window`
+    /// The first command dropped in a burst gets the `one_at_a_time` reply.
+    async fn starts_burst(&self, room: &Room, message: &Message) -> bool {
+        let Some(window) = self.config().command_burst_window else {
+            return true;
+        };
+        let notify = {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+            state
+                .command_bursts
+                .retain(|_, burst| now.duration_since(burst.started) < window);
+            let key = (room.room_id().to_owned(), message.sender.clone());
+            match state.command_bursts.get_mut(&key) {
+                Some(burst) => !std::mem::replace(&mut burst.notified, true),
+                None => {
+                    state.command_bursts.insert(
+                        key,
+                        CommandBurst {
+                            started: now,
+                            notified: false,
+                        },
+                    );
+                    return true;
+                }
+            }
+        };
+        info!("Dropping command {} sent in a burst", message.event_id());
+        if notify {
+            self.reply_text(room, message, self.strings().one_at_a_time)
+                .await;
+        }
+        false
+    }
+
+    /// Split a message into the command and its arguments, if it is a command
+    /// Tries the prefix first, then a mention of the bot if `mention_commands` is set.
+    fn split_command<'a>(&self, body: &'a str) -> Option<(&'a str, &'a str)> {
+        if self.has_mention_prefix() {
+            return self.split_mention_command(body);
+        }
+        if let Some(split) = self.split_prefix_command(body) {
+            return Some(split);
+        }
+        if self.config().mention_commands.unwrap_or(false) {
+            return self.split_mention_command(body);
+        }
+        None
+    }
+
+    /// Split a message that starts with the command prefix into the command and its arguments
+    /// Matches the prefix as set by the `prefix_config`.
+    fn split_prefix_command<'a>(&self, body: &'a str) -> Option<(&'a str, &'a str)> {
+        let prefix_config = self.config().prefix_config.clone().unwrap_or_default();
+        prefix_config.split_command(&self.command_prefix(), body)
+    }
+
+    /// Split a message addressed to the bot with a mention into the command and its arguments
+    fn split_mention_command<'a>(&self, body: &'a str) -> Option<(&'a str, &'a str)> {
+        // The mention replaces the prefix, so the rest is parsed without one
+        let user_id = self.user_id()?;
+        let text = strip_mention(body, &user_id, &self.name())?;
+        split_words(text)
+    }
+}
+
+/// Strip a leading mention of the bot from the message
+/// Matches the user ID, the localpart with an @, or the name of the bot, optionally followed by
+/// a ':' or ',' like clients add when completing a mention.
+fn strip_mention<'a>(body: &'a str, user_id: &UserId, name: &str) -> Option<&'a str> {
+    let localpart = format!("@{}", user_id.localpart());
+    let text = [user_id.as_str(), &localpart, name]
+        .into_iter()
+        .find_map(|mention| {
+            let rest = body.strip_prefix(mention)?;
+            let rest = rest
+                .strip_prefix(':')
+                .or_else(|| rest.strip_prefix(','))
+                .unwrap_or(rest);
+            // Don't match only the start of a longer word
+            (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim_start())
+        });
+    text
+}
+
+/// Strip the prefix from the text, ignoring the case of both
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let mut chars = text.chars();
+    for expected in prefix.chars() {
+        if !chars.next()?.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.as_str())
 }
 
 /// Verify if the sender is on the allow_list
-fn is_allowed(allow_list: Option<String>, sender: &str, username: &str) -> bool {
+fn is_allowed(allow_list: &RegexSet, sender: &str, username: &str) -> bool {
     // Check to see if it's from ourselves, in which case we should ignore it
-    if sender == username {
-        false
-    } else if let Some(allow_list) = allow_list {
-        let regex = Regex::new(&allow_list).expect("Invalid regular expression");
-        regex.is_match(sender)
-    } else {
-        false
+    sender != username && allow_list.is_match(sender)
+}
+
+/// Read the allow_list override stored in the room's account data
+async fn get_room_allow_list(room: &Room) -> Option<Vec<String>> {
+    let raw = match room.account_data(ROOM_ALLOW_LIST_TYPE.into()).await {
+        Ok(Some(raw)) => raw,
+        Ok(None) => return None,
+        Err(e) => {
+            error!(
+                "Error reading allow_list for room {}: {:?}",
+                room.room_id(),
+                e
+            );
+            return None;
+        }
+    };
+    match raw.deserialize_as::<RoomAllowListEvent>() {
+        Ok(event) => event.content.allow_list,
+        Err(e) => {
+            error!("Invalid allow_list for room {}: {:?}", room.room_id(), e);
+            None
+        }
+    }
+}
+
+/// Check if the user is mentioned in the message
+/// Uses the intentional mentions in `m.mentions` if the sender's client set them,
+/// otherwise falls back to looking for the user ID in the body.
+fn is_mentioned(event: &OriginalSyncRoomMessageEvent, user_id: &str) -> bool {
+    match &event.content.mentions {
+        Some(mentions) => mentions.user_ids.iter().any(|id| id.as_str() == user_id),
+        None => event.content.body().contains(user_id),
+    }
+}
+
+/// Reject a `command_prefix` that every message would start with
+pub(crate) fn check_command_prefix(command_prefix: Option<&str>) -> Result<(), HeadjackError> {
+    match command_prefix {
+        Some(prefix) if prefix.trim().is_empty() => Err(HeadjackError::Config(
+            "command_prefix is empty, so every message would be a command".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reject enabling `sliding_sync` without the feature, or together with `process_backfill`
+fn check_sliding_sync(config: &BotConfig) -> Result<(), HeadjackError> {
+    if !config.sliding_sync.unwrap_or(false) {
+        return Ok(());
+    }
+    if !cfg!(feature = "sliding-sync") {
+        return Err(HeadjackError::Config(
+            "sliding_sync needs headjack's sliding-sync feature".to_string(),
+        ));
+    }
+    if config.process_backfill.unwrap_or(false) {
+        return Err(HeadjackError::Config(
+            "process_backfill can't be used with sliding_sync".to_string(),
+        ));
     }
+    Ok(())
 }
 
 /// Check if the message is a command.
+/// Uses the default `PrefixConfig`, see `PrefixConfig::split_command` to match it differently.
 pub fn is_command(command_prefix: &str, text: &str) -> bool {
-    text.starts_with(command_prefix)
+    get_command(command_prefix, text).is_some()
 }
 
 /// Get the command, if it is a command.
+/// The prefix is matched literally and only once, and the command is the following word.
+/// Uses the default `PrefixConfig`, so a single character prefix like "!" must be followed
+/// directly by the command, which must start with a letter or number so that messages like "!!!"
+/// or "*emphasis*" aren't mistaken for commands. A longer prefix must be followed by whitespace.
 pub fn get_command<'a>(command_prefix: &str, text: &'a str) -> Option<&'a str> {
-    if text.starts_with(command_prefix) {
-        text.trim_start_matches(command_prefix)
-            .split_whitespace()
-            .next()
+    let (command, _) = PrefixConfig::default().split_command(command_prefix, text)?;
+    Some(command)
+}
+
+/// Get the arguments of the command, if it is a command.
+/// This is everything after the command, with the surrounding whitespace trimmed.
+/// Line breaks are kept, so a multi-line message like "!bot paste\n<text>" has the command on
+/// the first line and all the following lines as the arguments.
+pub fn get_args<'a>(command_prefix: &str, text: &'a str) -> Option<&'a str> {
+    let (_, args) = PrefixConfig::default().split_command(command_prefix, text)?;
+    Some(args)
+}
+
+/// Split text into its first word and the rest, with the surrounding whitespace trimmed
+fn split_words(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    let command = text.split_whitespace().next()?;
+    Some((command, text[command.len()..].trim()))
+}
+
+/// Randomize a retry delay in seconds by ±50%
+/// Keeps bots that failed at the same time, e.g. because the homeserver restarted, from all
+/// retrying at the same moment.
+fn with_jitter(delay: u64) -> Duration {
+    Duration::from_secs_f64(delay as f64 * thread_rng().gen_range(0.5..1.5))
+}
+
+/// Call the text handler with the message, logging any error
+async fn call_text_handler<F, Fut>(
+    callback: F,
+    message: Message,
+    room: Room,
+    log_message_bodies: bool,
+) where
+    F: FnOnce(Message, Room) -> Fut,
+    Fut: std::future::Future<Output = Result<(), ()>>,
+{
+    let span = info_span!(
+        "text_handler",
+        room_id = %room.room_id(),
+        sender = %message.sender
+    );
+    let event_id = message.event_id().to_owned();
+    let body = message.body.clone();
+    if let Err(e) = callback(message, room).instrument(span).await {
+        if log_message_bodies {
+            error!("Error responding to: {}\nError: {:?}", body, e);
+        } else {
+            error!(
+                "Error responding to {} ({} bytes)\nError: {:?}",
+                event_id,
+                body.len(),
+                e
+            );
+        }
+    }
+}
+
+/// Sync once, logging periodically while the sync is running so a slow sync isn't mistaken for
+/// a hang
+async fn sync_once_with_progress(
+    client: &Client,
+    sync_settings: SyncSettings,
+    started: Instant,
+) -> matrix_sdk::Result<SyncResponse> {
+    let sync = client.sync_once(sync_settings);
+    tokio::pin!(sync);
+    let start = tokio::time::Instant::now() + INITIAL_SYNC_LOG_INTERVAL;
+    let mut progress = tokio::time::interval_at(start, INITIAL_SYNC_LOG_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut sync => return result,
+            _ = progress.tick() => {
+                info!(
+                    "Initial sync still running after {}",
+                    format_duration(started.elapsed())
+                );
+            }
+        }
+    }
+}
+
+/// Format a duration as a human readable string, e.g. "1d 2h 3m 4s"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        secs / 86400,
+        (secs % 86400) / 3600,
+        (secs % 3600) / 60,
+        secs % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, seconds)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
     } else {
-        None
+        format!("{}s", seconds)
     }
 }
 
@@ -597,7 +2955,10 @@ fn expand_tilde(path: &str) -> String {
 }
 
 /// Restore a previous session.
-async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<String>)> {
+async fn restore_session(
+    session_file: &Path,
+    http: &HttpSettings,
+) -> Result<(Client, FullSession), HeadjackError> {
     info!(
         "Previous session found in '{}'",
         session_file.to_string_lossy()
@@ -605,52 +2966,84 @@ async fn restore_session(session_file: &Path) -> anyhow::Result<(Client, Option<
 
     // The session was serialized as JSON in a file.
     let serialized_session = fs::read_to_string(session_file).await?;
+    let full_session: FullSession =
+        serde_json::from_str(&serialized_session).map_err(HeadjackError::SessionCorrupt)?;
     let FullSession {
         client_session,
         user_session,
-        sync_token,
-    } = serde_json::from_str(&serialized_session)?;
+        ..
+    } = &full_session;
 
     // Build the client with the previous settings from the session.
-    let client = Client::builder()
-        .homeserver_url(client_session.homeserver)
-        .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
-        .build()
-        .await?;
+    let builder = http
+        .apply(Client::builder())
+        .homeserver_url(&client_session.homeserver)
+        .sqlite_store(
+            &client_session.db_path,
+            Some(
+                &client_session
+                    .passphrase_store
+                    .load(client_session.passphrase.clone())?,
+            ),
+        );
+    let client = http.build(builder).await?;
 
     info!("Restoring session for {}…", &user_session.meta.user_id);
 
     // Restore the Matrix user session.
-    client.restore_session(user_session).await?;
+    client.restore_session(user_session.clone()).await?;
 
     info!("Done!");
 
-    Ok((client, sync_token))
+    Ok((client, full_session))
 }
 
 /// Login with a new device.
+/// The session is only persisted if a session file is given, and is returned if it was persisted.
 async fn login(
     state_dir: &Path,
-    session_file: &Path,
+    session_file: Option<&Path>,
+    store: &StoreConfig,
     homeserver_url: &str,
     username: &str,
     password: &Option<String>,
-) -> anyhow::Result<Client> {
+    http: &HttpSettings,
+) -> Result<(Client, Option<FullSession>), HeadjackError> {
     info!("No previous session found, logging in…");
 
-    let (client, client_session) = build_client(state_dir, homeserver_url.to_owned()).await?;
+    let (client, client_session) = build_client(state_dir, store, homeserver_url, http)
+        .await
+        .inspect_err(|e| error!("Could not set up the client for {}: {}", homeserver_url, e))?;
     let matrix_auth = client.matrix_auth();
 
+    // Make sure the homeserver is reachable before asking for the password, the errors from a
+    // failed login are hard to interpret
+    let login_types = matrix_auth.get_login_types().await.inspect_err(|e| {
+        error!(
+            "Could not reach the homeserver at {}: {}",
+            client.homeserver(),
+            e
+        )
+    })?;
+    if !login_types
+        .flows
+        .iter()
+        .any(|flow| matches!(flow, LoginType::Password(_)))
+    {
+        return Err(HeadjackError::Config(format!(
+            "the homeserver at {} doesn't support password login",
+            client.homeserver()
+        )));
+    }
+
     // If there's no password, ask for it
     let password = match password {
         Some(password) => password.clone(),
         None => {
             print!("Password: ");
-            io::stdout().flush().expect("Unable to write to stdout");
+            io::stdout().flush()?;
             let mut password = String::new();
-            io::stdin()
-                .read_line(&mut password)
-                .expect("Unable to read user input");
+            io::stdin().read_line(&mut password)?;
             password.trim().to_owned()
         }
     };
@@ -665,86 +3058,246 @@ async fn login(
         }
         Err(error) => {
             error!("Error logging in: {error}");
-            return Err(error.into());
+            return Err(HeadjackError::login(error));
         }
     }
 
     // Persist the session to reuse it later.
-    let user_session = matrix_auth
-        .session()
-        .expect("A logged-in client should have a session");
-    let serialized_session = serde_json::to_string(&FullSession {
+    let (Some(session_file), Some(client_session)) = (session_file, client_session) else {
+        return Ok((client, None));
+    };
+    let user_session = matrix_auth.session().ok_or(HeadjackError::NotLoggedIn)?;
+    let full_session = FullSession {
         client_session,
         user_session,
         sync_token: None,
-    })?;
-    fs::write(session_file, serialized_session).await?;
+    };
+    let serialized_session = serde_json::to_string(&full_session)?;
+    write_atomic(session_file, serialized_session).await?;
 
     info!("Session persisted in {}", session_file.to_string_lossy());
 
-    Ok(client)
+    Ok((client, Some(full_session)))
 }
 
 /// Build a new client.
+/// Returns the data needed to rebuild the client, if the store can be restored.
 async fn build_client(
     state_dir: &Path,
-    homeserver: String,
-) -> anyhow::Result<(Client, ClientSession)> {
+    store: &StoreConfig,
+    homeserver: &str,
+    http: &HttpSettings,
+) -> Result<(Client, Option<ClientSession>), HeadjackError> {
+    let StoreConfig::Sqlite {
+        path,
+        passphrase,
+        passphrase_store,
+    } = store
+    else {
+        // The SDK uses the in-memory store if no other store is configured
+        let client = http.build(http.apply(client_builder(homeserver))).await?;
+        return Ok((client, None));
+    };
+
     let mut rng = thread_rng();
 
     // Place the db into a subfolder, just in case multiple clients are running
-    let db_subfolder: String = (&mut rng)
-        .sample_iter(Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
-    let db_path = state_dir.join(db_subfolder);
+    let db_path = match path {
+        Some(path) => path.clone(),
+        None => {
+            let db_subfolder: String = (&mut rng)
+                .sample_iter(Alphanumeric)
+                .take(7)
+                .map(char::from)
+                .collect();
+            state_dir.join(db_subfolder)
+        }
+    };
 
     // Generate a random passphrase.
-    // It will be saved in the session file and used to encrypt the database.
-    let passphrase: String = (&mut rng)
-        .sample_iter(Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
-
-    match Client::builder()
-        .homeserver_url(&homeserver)
+    // It will be saved according to the passphrase store and used to encrypt the database.
+    let passphrase_store = passphrase_store.clone().unwrap_or_default();
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase.clone(),
+        None => (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect(),
+    };
+    let passphrase = passphrase_store.passphrase(passphrase)?;
+
+    let builder = http
+        .apply(client_builder(homeserver))
         // We use the SQLite store, which is enabled by default. This is the crucial part to
         // persist the encryption setup.
         // Note that other store backends are available and you can even implement your own.
-        .sqlite_store(&db_path, Some(&passphrase))
-        .build()
-        .await
-    {
-        Ok(client) => Ok((
-            client,
-            ClientSession {
-                homeserver,
+        .sqlite_store(&db_path, Some(&passphrase));
+    match http.build(builder).await {
+        Ok(client) => {
+            // Store the resolved URL, so that restoring doesn't need to discover it again
+            let client_session = ClientSession {
+                homeserver: client.homeserver().to_string(),
                 db_path,
-                passphrase,
-            },
-        )),
+                passphrase: passphrase_store.save(&passphrase)?,
+                passphrase_store,
+            };
+            Ok((client, Some(client_session)))
+        }
         Err(error) => Err(error.into()),
     }
 }
 
-/// Write the sync_token to the session file
-async fn persist_sync_token(session_file: &Path, sync_token: String) -> anyhow::Result<()> {
-    let serialized_session = fs::read_to_string(session_file).await?;
-    let mut full_session: FullSession = serde_json::from_str(&serialized_session)?;
+/// The settings for the HTTP client, see `Bot::http_settings`
+#[derive(Debug, Clone)]
+struct HttpSettings {
+    /// Retries and timeouts of the requests
+    request_config: RequestConfig,
+    /// The User-Agent header sent with every request
+    user_agent: String,
+    /// The proxy to connect through
+    proxy: Option<String>,
+    /// Skip verifying the TLS certificate of the homeserver
+    accept_invalid_certs: bool,
+    /// The user's customization of the client, see `client_builder_hook`
+    builder_hook: Option<ClientBuilderHook>,
+    /// The sliding sync proxy to use instead of the one from .well-known
+    #[cfg(feature = "sliding-sync")]
+    sliding_sync_proxy: Option<String>,
+}
 
-    full_session.sync_token = Some(sync_token);
-    let serialized_session = serde_json::to_string(&full_session)?;
-    fs::write(session_file, serialized_session).await?;
+impl HttpSettings {
+    /// Apply the settings to a client builder
+    fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        let builder = builder
+            .request_config(self.request_config)
+            .user_agent(&self.user_agent);
+        let builder = match &self.proxy {
+            Some(proxy) => builder.proxy(proxy),
+            None => builder,
+        };
+        #[cfg(feature = "sliding-sync")]
+        let builder = match &self.sliding_sync_proxy {
+            Some(url) => builder.sliding_sync_proxy(url),
+            None => builder,
+        };
+        if self.accept_invalid_certs {
+            warn!("TLS certificate verification is disabled, this is INSECURE");
+            builder.disable_ssl_verification()
+        } else {
+            builder
+        }
+    }
+
+    /// Build the client, after the `client_builder_hook` customized the builder
+    async fn build(&self, builder: ClientBuilder) -> Result<Client, matrix_sdk::ClientBuildError> {
+        match &self.builder_hook {
+            Some(hook) => (hook.0)(builder).build().await,
+            None => builder.build().await,
+        }
+    }
+}
+
+/// Create a client builder for the homeserver
+/// Accepts either the URL of the homeserver, or a server name like "example.org", which is
+/// resolved to the real homeserver using .well-known discovery.
+fn client_builder(homeserver: &str) -> ClientBuilder {
+    if !homeserver.contains("://") {
+        if let Ok(server_name) = <&ServerName>::try_from(homeserver) {
+            return Client::builder().server_name(server_name);
+        }
+    }
+    Client::builder().homeserver_url(homeserver)
+}
+
+/// Whether a sync error can't be fixed by retrying
+/// That's the case when the homeserver no longer accepts our access token.
+fn is_fatal_sync_error(error: &Error) -> bool {
+    matches!(
+        error.client_api_error_kind(),
+        Some(
+            ErrorKind::UnknownToken { .. }
+                | ErrorKind::MissingToken
+                | ErrorKind::Forbidden
+                | ErrorKind::UserDeactivated
+        )
+    )
+}
+
+/// Describe a sync error in one line, classified as a network, authentication or homeserver error
+/// The errors of the HTTP client include the URL of the sync, which has the whole filter in it,
+/// so the full error is only logged at the trace level.
+fn describe_sync_error(error: &Error) -> String {
+    if let Error::Http(HttpError::Reqwest(e)) = error {
+        let cause = if e.is_timeout() {
+            "the request timed out".to_string()
+        } else if e.is_connect() {
+            "couldn't connect to the homeserver".to_string()
+        } else {
+            // The source is the error without the URL
+            std::error::Error::source(e)
+                .map_or_else(|| "request failed".to_string(), ToString::to_string)
+        };
+        return format!("network error: {}", cause);
+    }
+    let Some(api_error) = error.as_client_api_error() else {
+        return error.to_string();
+    };
+    let status = api_error.status_code;
+    match error.client_api_error_kind() {
+        Some(
+            kind @ (ErrorKind::UnknownToken { .. }
+            | ErrorKind::MissingToken
+            | ErrorKind::Forbidden
+            | ErrorKind::UserDeactivated),
+        ) => format!("authentication error: {}", kind),
+        Some(ErrorKind::LimitExceeded { .. }) => "rate limited by the homeserver".to_string(),
+        _ if status.is_server_error() => format!("homeserver error: HTTP {}", status),
+        Some(kind) => format!("homeserver rejected the sync: {} (HTTP {})", kind, status),
+        None => format!("unexpected response from the homeserver: HTTP {}", status),
+    }
+}
+
+/// Write the contents to a file atomically
+/// Writes to a temporary file first and then renames it over the target, so that a crash
+/// mid-write can't leave a truncated file behind.
+async fn write_atomic(path: &Path, contents: String) -> Result<(), HeadjackError> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp_path, path).await?;
 
     Ok(())
 }
 
+/// Wait until the room is joined and its members have come down a sync
+/// `Room::join` returns before the sync that has the room's state, so the member count used for
+/// `room_size_limit` isn't known yet. Returns false if the room isn't joined by the timeout.
+async fn wait_for_join(room: &Room) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < JOIN_TIMEOUT {
+        if room.state() == RoomState::Joined && room.joined_members_count() > 0 {
+            return true;
+        }
+        sleep(JOIN_POLL_INTERVAL).await;
+    }
+    // The members can still be fetched once joined, so only the membership is required
+    room.state() == RoomState::Joined
+}
+
 /// Check if the room exceeds the size limit
+/// Uses the member count from the room summary if it's known, to avoid fetching every member.
 async fn is_room_too_large(room: &Room, room_size_limit: Option<usize>) -> bool {
     if let Some(room_size_limit) = room_size_limit {
-        if let Ok(members) = room.members(RoomMemberships::ACTIVE).await {
+        let joined = room.joined_members_count();
+        if joined > 0 {
+            joined as usize > room_size_limit
+        } else if let Ok(members) = room.members(RoomMemberships::ACTIVE).await {
             members.len() > room_size_limit
         } else {
             false
@@ -753,3 +3306,563 @@ async fn is_room_too_large(room: &Room, room_size_limit: Option<usize>) -> bool
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestBot;
+    use futures_util::future::join_all;
+    use matrix_sdk::ruma::{room_id, user_id};
+    use std::sync::atomic::AtomicUsize;
+
+    /// A config that allows commands from "@alice:localhost"
+    fn test_config() -> BotConfig {
+        BotConfig {
+            allow_list: vec!["@alice:localhost".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_commands_limits_running_commands() {
+        let config = BotConfig {
+            max_concurrent_commands: Some(2),
+            ..test_config()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        let counters = (running.clone(), max_running.clone(), finished.clone());
+        bot.register_text_command("slow", None, None, |_, _| async move {
+            let (running, max_running, finished) = counters;
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            sleep(Duration::from_millis(200)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+            finished.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        let sender = user_id!("@alice:localhost");
+        let results =
+            join_all((0..6).map(|_| bot.inject_message(room_id, sender, "!bot slow"))).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        // The commands over the limit got the busy reply instead of running
+        let busy = bot
+            .sent_bodies()
+            .await
+            .iter()
+            .filter(|body| body.ends_with(&Strings::default().busy))
+            .count();
+        assert!(busy > 0);
+        assert_eq!(finished.load(Ordering::SeqCst) + busy, 6);
+    }
+
+    #[tokio::test]
+    async fn replayed_messages_reach_the_commands() {
+        let bot = TestBot::new(test_config()).await.unwrap();
+        let echo = bot.bot().clone();
+        bot.register_text_command("echo", None, None, |message, room| async move {
+            let content = RoomMessageEventContent::text_plain(message.args);
+            echo.send(&room, content).await.map_err(|_| ())?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        let sender = user_id!("@alice:localhost");
+        bot.inject_message(room_id, sender, "!bot echo synced")
+            .await
+            .unwrap();
+        let room = bot.client().unwrap().get_room(room_id).unwrap();
+        let event = serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$replayed:localhost",
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": { "msgtype": "m.text", "body": "!bot echo replayed" }
+        }))
+        .unwrap();
+        bot.replay_event(event, &room).await;
+
+        assert_eq!(bot.sent_bodies().await, ["synced", "replayed"]);
+    }
+
+    #[tokio::test]
+    async fn thread_variants_send_to_the_thread() {
+        let bot = TestBot::new(test_config()).await.unwrap();
+        let room_id = room_id!("!room:localhost");
+        let root = bot
+            .inject_message(room_id, user_id!("@alice:localhost"), "root")
+            .await
+            .unwrap();
+        let room = bot.client().unwrap().get_room(room_id).unwrap();
+
+        bot.send_file(&room, "a.txt", &mime::TEXT_PLAIN, b"a".to_vec())
+            .await
+            .unwrap();
+        bot.send_file_in_thread(
+            &room,
+            Some(&root),
+            "b.txt",
+            &mime::TEXT_PLAIN,
+            b"b".to_vec(),
+        )
+        .await
+        .unwrap();
+        bot.send_location_in_thread(&room, Some(&root), "geo:1,2", "here")
+            .await
+            .unwrap();
+        let vars = HashMap::new();
+        bot.send_template_in_thread(&room, Some(&root), "hi", &vars)
+            .await
+            .unwrap();
+
+        let sent = bot.sent().await;
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sent[0].content["msgtype"], "m.file");
+        assert!(sent[0].content.get("m.relates_to").is_none());
+        for event in &sent[1..] {
+            assert_eq!(event.content["m.relates_to"]["rel_type"], "m.thread");
+            assert_eq!(event.content["m.relates_to"]["event_id"], root.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn ordered_sends_drop_idle_queues() {
+        let config = BotConfig {
+            ordered_sends: Some(true),
+            ..test_config()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let room_id = room_id!("!room:localhost");
+        bot.inject_message(room_id, user_id!("@alice:localhost"), "hi")
+            .await
+            .unwrap();
+        let room = bot.client().unwrap().get_room(room_id).unwrap();
+
+        let sends =
+            (0..5).map(|i| bot.send(&room, RoomMessageEventContent::text_plain(i.to_string())));
+        assert!(join_all(sends).await.iter().all(Result::is_ok));
+        bot.send_file(&room, "a.txt", &mime::TEXT_PLAIN, b"a".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(bot.sent_bodies().await, ["0", "1", "2", "3", "4", "a.txt"]);
+        assert!(bot.state.lock().await.send_queues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_sync_token_writes_are_retried() {
+        let state_dir =
+            std::env::temp_dir().join(format!("headjack-token-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        let config = BotConfig {
+            state_dir: Some(state_dir.to_string_lossy().into_owned()),
+            ..test_config()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let user_session = bot.client().unwrap().matrix_auth().session().unwrap();
+        {
+            let mut state = bot.state.lock().await;
+            state.session = Some(FullSession {
+                client_session: ClientSession {
+                    homeserver: "http://localhost".to_string(),
+                    db_path: state_dir.join("db"),
+                    passphrase: None,
+                    passphrase_store: PassphraseStore::default(),
+                },
+                user_session,
+                sync_token: None,
+            });
+            state.pending_sync_token = Some("batch_1".to_string());
+        }
+
+        // The state dir doesn't exist yet, so the write fails
+        assert!(bot.flush_sync_token().await.is_err());
+        std::fs::create_dir_all(&state_dir).unwrap();
+        bot.flush_sync_token().await.unwrap();
+
+        let session = std::fs::read_to_string(state_dir.join("session")).unwrap();
+        let _ = std::fs::remove_dir_all(&state_dir);
+        assert!(session.contains("batch_1"));
+    }
+
+    #[tokio::test]
+    async fn edits_are_not_recorded_as_turns() {
+        let state_dir = std::env::temp_dir().join(format!("headjack-test-{}", std::process::id()));
+        let config = BotConfig {
+            max_session_turns: Some(10),
+            state_dir: Some(state_dir.to_string_lossy().into_owned()),
+            ..test_config()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        bot.register_text_handler(|_, _| async { Ok(()) }).unwrap();
+
+        let room_id = room_id!("!edits:localhost");
+        let sender = user_id!("@alice:localhost");
+        let original = bot.inject_message(room_id, sender, "hello").await.unwrap();
+        let edit = serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$edit:localhost",
+            "sender": sender,
+            "origin_server_ts": 0,
+            "content": {
+                "msgtype": "m.text",
+                "body": "* hello there",
+                "m.new_content": { "msgtype": "m.text", "body": "hello there" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": original }
+            }
+        });
+        bot.inject_event(room_id, edit).await.unwrap();
+
+        let session = bot.session(room_id).await.unwrap();
+        let bodies: Vec<_> = session.iter().map(|turn| turn.body.as_str()).collect();
+        assert_eq!(bodies, ["hello"]);
+        bot.session_clear(room_id).await.unwrap();
+        let _ = std::fs::remove_dir_all(state_dir);
+    }
+
+    #[test]
+    fn prefix_config_combinations() {
+        use PrefixSpace::{Forbidden, Optional, Required};
+        let help = Some(("help", ""));
+        let bot_help = Some(("bot", "help"));
+        #[rustfmt::skip]
+        let cases = [
+            // prefix, space, case sensitive, message, expected
+            ("!", Forbidden, true, "!help", help),
+            ("!", Forbidden, true, "! help", None),
+            ("!", Forbidden, true, "!bot help", bot_help),
+            ("!", Required, true, "!help", None),
+            ("!", Required, true, "! help", help),
+            ("!", Required, true, "!bot help", None),
+            ("!", Required, false, "!help", None),
+            ("!", Required, false, "! help", help),
+            ("!", Required, false, "!bot help", None),
+            ("!", Optional, true, "!help", help),
+            ("!", Optional, true, "! help", help),
+            ("!", Optional, true, "!bot help", bot_help),
+            ("!", Optional, false, "!help", help),
+            ("!", Optional, false, "! help", help),
+            ("!", Optional, false, "!bot help", bot_help),
+            ("!bot", Required, true, "!help", None),
+            ("!bot", Required, true, "! help", None),
+            ("!bot", Required, true, "!bot help", help),
+            ("!bot", Required, true, "!BOT help", None),
+            ("!bot", Required, true, "!bothelp", None),
+            ("!bot", Required, false, "!help", None),
+            ("!bot", Required, false, "! help", None),
+            ("!bot", Required, false, "!bot help", help),
+            ("!bot", Required, false, "!BOT help", help),
+            ("!bot", Required, false, "!bothelp", None),
+            ("!bot", Optional, true, "!help", None),
+            ("!bot", Optional, true, "! help", None),
+            ("!bot", Optional, true, "!bot help", help),
+            ("!bot", Optional, true, "!BOT help", None),
+            ("!bot", Optional, true, "!bothelp", help),
+            ("!bot", Optional, false, "!help", None),
+            ("!bot", Optional, false, "! help", None),
+            ("!bot", Optional, false, "!bot help", help),
+            ("!bot", Optional, false, "!BOT help", help),
+            ("!bot", Optional, false, "!BOThelp", help),
+        ];
+        for (prefix, space, case_sensitive, text, expected) in cases {
+            let config = PrefixConfig {
+                space: Some(space),
+                case_sensitive: Some(case_sensitive),
+            };
+            assert_eq!(
+                config.split_command(prefix, text),
+                expected,
+                "{:?} with prefix {:?}, {:?}, case sensitive: {}",
+                text,
+                prefix,
+                space,
+                case_sensitive
+            );
+        }
+    }
+
+    #[test]
+    fn default_prefix_config_matches_the_public_helpers() {
+        for (prefix, text) in [
+            ("!", "!help"),
+            ("!", "! help"),
+            ("!", "!bot help"),
+            ("!bot", "!bot help"),
+            ("!bot ", "!bot help me"),
+            ("!bot", "!bothelp"),
+            ("!bot", "!BOT help"),
+        ] {
+            let split = PrefixConfig::default().split_command(prefix, text);
+            assert_eq!(get_command(prefix, text), split.map(|(command, _)| command));
+            assert_eq!(get_args(prefix, text), split.map(|(_, args)| args));
+            assert_eq!(is_command(prefix, text), split.is_some());
+        }
+        assert_eq!(get_command("!bot ", "!bot help me"), Some("help"));
+        assert_eq!(get_args("!bot ", "!bot help me"), Some("me"));
+        assert_eq!(get_command("!bot", "!bothelp"), None);
+    }
+
+    #[tokio::test]
+    async fn dispatcher_uses_the_prefix_config() {
+        let config = BotConfig {
+            command_prefix: Some("!".to_string()),
+            prefix_config: Some(PrefixConfig {
+                space: Some(PrefixSpace::Required),
+                case_sensitive: Some(false),
+            }),
+            ..test_config()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let responder = bot.bot().clone();
+        bot.register_text_command("echo", None, None, |message, room| async move {
+            let content = RoomMessageEventContent::text_plain(message.args);
+            responder.send(&room, content).await.map_err(|_| ())?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        let sender = user_id!("@alice:localhost");
+        for text in ["!echo no space", "! echo spaced"] {
+            bot.inject_message(room_id, sender, text).await.unwrap();
+            let prefix_config = bot.config().prefix_config.clone().unwrap();
+            assert_eq!(
+                bot.split_command(text),
+                prefix_config.split_command("!", text)
+            );
+        }
+        assert_eq!(bot.sent_bodies().await, ["spaced"]);
+    }
+
+    #[test]
+    fn tokenization_keeps_quotes_in_the_args() {
+        assert_eq!(get_command("!", "!echo \"a b\" c"), Some("echo"));
+        assert_eq!(get_args("!", "!echo \"a b\" c"), Some("\"a b\" c"));
+        assert_eq!(get_args("!bot ", "!bot echo 'single'"), Some("'single'"));
+        // A quoted command isn't a command
+        assert_eq!(get_command("!", "!\"echo\" a"), None);
+    }
+
+    #[test]
+    fn tokenization_of_escapes_and_markdown() {
+        // An escaped prefix isn't a prefix
+        assert!(!is_command("!", "\\!help"));
+        assert!(!is_command("!", "!\\help"));
+        // Backslashes inside a word are kept
+        assert_eq!(get_command("!", "!help\\ me"), Some("help\\"));
+        assert_eq!(get_args("!", "!help\\ me"), Some("me"));
+        // Markdown after a single character prefix isn't a command
+        assert!(!is_command("!", "!**bold**"));
+        assert!(!is_command("!", "!!!"));
+        assert!(!is_command("*", "**bold**"));
+        assert!(!is_command("*", "* list item"));
+    }
+
+    #[test]
+    fn tokenization_matches_the_prefix_literally() {
+        // Regex special characters only match themselves
+        assert_eq!(get_command(".", ".help"), Some("help"));
+        assert!(!is_command(".", "xhelp"));
+        assert_eq!(get_command("$", "$help"), Some("help"));
+        assert!(!is_command("$", "help$"));
+        assert_eq!(get_command("[bot]", "[bot] help"), Some("help"));
+        assert!(!is_command("[bot]", "b help"));
+        // A multi-byte single character prefix
+        assert_eq!(get_command("→", "→help"), Some("help"));
+        // The prefix is only stripped once
+        assert!(!is_command("!", "!!help"));
+    }
+
+    #[test]
+    fn tokenization_of_empty_input() {
+        for text in ["", " ", "!", "! ", "!\n"] {
+            assert!(!is_command("!", text), "{:?}", text);
+            assert_eq!(get_args("!", text), None, "{:?}", text);
+        }
+        for text in ["", "!bot", "!bot ", "!bot \n "] {
+            assert!(!is_command("!bot ", text), "{:?}", text);
+        }
+        assert_eq!(get_args("!", "!help"), Some(""));
+        assert_eq!(get_args("!", "!help   "), Some(""));
+    }
+
+    #[test]
+    fn tokenization_of_multi_line_commands() {
+        let text = "!bot paste\nfirst line\n  second line\n";
+        assert_eq!(get_command("!bot ", text), Some("paste"));
+        assert_eq!(get_args("!bot ", text), Some("first line\n  second line"));
+    }
+
+    #[tokio::test]
+    async fn two_bots_in_one_room_keep_separate_help() {
+        let room_id = room_id!("!room:localhost");
+        let sender = user_id!("@alice:localhost");
+        // Both bots are "@bot:localhost", only their prefixes differ
+        let mut bots = Vec::new();
+        for (prefix, command) in [("!a", "alpha"), ("!b", "beta")] {
+            let config = BotConfig {
+                command_prefix: Some(prefix.to_string()),
+                ..test_config()
+            };
+            let bot = TestBot::new(config).await.unwrap();
+            let help = Some(format!("The {} command", command));
+            bot.register_text_command(command, None, help, |_, _| async move { Ok(()) })
+                .await
+                .unwrap();
+            bot.register_help_command().await.unwrap();
+            bots.push(bot);
+        }
+
+        for bot in &bots {
+            bot.inject_message(room_id, sender, "!a help")
+                .await
+                .unwrap();
+        }
+        let help = bots[0].sent_bodies().await;
+        assert_eq!(help.len(), 1);
+        assert!(help[0].contains("!a alpha"));
+        assert!(!help[0].contains("beta"));
+        // The other bot wasn't addressed
+        assert!(bots[1].sent_bodies().await.is_empty());
+
+        bots[1]
+            .inject_message(room_id, sender, "!b help")
+            .await
+            .unwrap();
+        let help = bots[1].sent_bodies().await;
+        assert_eq!(help.len(), 1);
+        assert!(help[0].contains("!b beta"));
+        assert!(!help[0].contains("alpha"));
+    }
+
+    #[tokio::test]
+    async fn reload_config_keeps_the_old_prefix_if_the_new_one_is_empty() {
+        let config = BotConfig {
+            command_prefix: Some("!".to_string()),
+            ..test_config()
+        };
+        let bot = Bot::new(config.clone()).await;
+        for prefix in ["", "  "] {
+            bot.reload_config(BotConfig {
+                command_prefix: Some(prefix.to_string()),
+                ..config.clone()
+            });
+            assert_eq!(bot.command_prefix(), "!");
+        }
+        bot.reload_config(BotConfig {
+            command_prefix: Some("?".to_string()),
+            ..config
+        });
+        assert_eq!(bot.command_prefix(), "?");
+    }
+
+    #[tokio::test]
+    async fn room_allow_list_changes_drop_the_cached_override() {
+        let bot = TestBot::new(test_config()).await.unwrap();
+        let room_id = room_id!("!room:localhost");
+        let sender = "@alice:localhost";
+        bot.inject_message(room_id, user_id!("@carol:localhost"), "hi")
+            .await
+            .unwrap();
+        let room = bot.client().unwrap().get_room(room_id).unwrap();
+        let username = bot.full_name().unwrap();
+        assert!(bot.is_allowed_in_room(&room, sender, &username).await);
+
+        bot.inject_room_account_data(
+            room_id,
+            ROOM_ALLOW_LIST_TYPE,
+            serde_json::json!({ "allow_list": ["@bob:localhost"] }),
+        )
+        .await
+        .unwrap();
+        assert!(!bot.is_allowed_in_room(&room, sender, &username).await);
+        assert!(
+            bot.is_allowed_in_room(&room, "@bob:localhost", &username)
+                .await
+        );
+
+        bot.inject_room_account_data(room_id, ROOM_ALLOW_LIST_TYPE, serde_json::json!({}))
+            .await
+            .unwrap();
+        assert!(bot.is_allowed_in_room(&room, sender, &username).await);
+    }
+
+    #[tokio::test]
+    async fn rooms_command_escapes_room_names() {
+        let bot = TestBot::new(test_config()).await.unwrap();
+        bot.register_rooms_command().await.unwrap();
+        let room_id = room_id!("!rooms:localhost");
+        let sender = user_id!("@alice:localhost");
+        let state_event = |event_type: &str, state_key: &str, content| {
+            serde_json::json!({
+                "type": event_type,
+                "state_key": state_key,
+                "event_id": format!("${event_type}:localhost"),
+                "sender": sender,
+                "origin_server_ts": 0,
+                "content": content
+            })
+        };
+        for event in [
+            state_event(
+                "m.room.member",
+                sender.as_str(),
+                serde_json::json!({ "membership": "join" }),
+            ),
+            state_event(
+                "m.room.power_levels",
+                "",
+                serde_json::json!({ "users": { sender.as_str(): 100 } }),
+            ),
+            state_event(
+                "m.room.name",
+                "",
+                serde_json::json!({ "name": "[x](http://evil)" }),
+            ),
+        ] {
+            bot.inject_event(room_id, event).await.unwrap();
+        }
+
+        bot.inject_message(room_id, sender, "!bot rooms")
+            .await
+            .unwrap();
+
+        let sent = bot.sent().await;
+        let reply = sent.last().unwrap();
+        let formatted = reply.content["formatted_body"].as_str().unwrap();
+        assert!(formatted.contains("[x](http://evil)"));
+        assert!(!formatted.contains("href"));
+    }
+
+    #[tokio::test]
+    async fn streamed_deltas_are_split_over_messages() {
+        let bot = TestBot::new(test_config()).await.unwrap();
+        let room_id = room_id!("!stream:localhost");
+        bot.inject_message(room_id, user_id!("@carol:localhost"), "hi")
+            .await
+            .unwrap();
+        let room = bot.client().unwrap().get_room(room_id).unwrap();
+
+        // Multi-byte chars, so the split has to find a char boundary
+        let delta = "é".repeat(10_000);
+        let mut stream = bot.stream_response(&room);
+        stream.push(&delta).await.unwrap();
+        stream.finish().await.unwrap();
+
+        let bodies = bot.sent_bodies().await;
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies.iter().all(|body| body.len() <= 16_000));
+        assert_eq!(bodies.concat(), delta);
+    }
+}
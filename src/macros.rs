@@ -0,0 +1,40 @@
+//! Support for declaring commands with the `#[command]` attribute.
+//!
+//! The attribute submits a `CommandRegistration` to a global registry using `inventory`,
+//! and `Bot::run` registers everything in it before starting to sync.
+
+use crate::{Bot, HeadjackError, Message};
+use futures_util::future::BoxFuture;
+pub use headjack_macros::command;
+use matrix_sdk::Room;
+
+/// A command declared with the `#[command]` attribute
+/// Created by the macro, there should be no need to construct this yourself.
+pub struct CommandRegistration {
+    /// The command string that triggers this command
+    pub name: &'static str,
+    /// Argument format
+    pub args: Option<&'static str>,
+    /// Single line of help text
+    pub help: Option<&'static str>,
+    /// Calls the declared function
+    pub handler: fn(Message, Room) -> BoxFuture<'static, Result<(), ()>>,
+}
+
+inventory::collect!(CommandRegistration);
+
+impl Bot {
+    /// Register all the commands declared with the `#[command]` attribute
+    pub(crate) async fn register_declared_commands(&self) -> Result<(), HeadjackError> {
+        for registration in inventory::iter::<CommandRegistration> {
+            self.register_text_command(
+                registration.name,
+                registration.args.map(str::to_string),
+                registration.help.map(str::to_string),
+                registration.handler,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
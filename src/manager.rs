@@ -0,0 +1,61 @@
+//! Running several bots in one process, e.g. for a service hosting bots for different accounts.
+//!
+//! Each `Bot` already has its own client, state and configuration, so the manager only runs them
+//! side by side and collects how each of them stopped.
+
+use crate::{Bot, HeadjackError};
+use futures_util::future::join_all;
+use tracing::{error, info_span, Instrument};
+
+/// Runs several bots concurrently
+#[derive(Default)]
+pub struct BotManager {
+    /// The bots, in the order they were added
+    bots: Vec<Bot>,
+}
+
+impl BotManager {
+    /// Create a manager without any bots, add them with `add`
+    pub fn new() -> Self {
+        BotManager::default()
+    }
+
+    /// Add a bot, which should already be logged in and have its commands registered
+    /// Fails if another bot uses the same state directory, as they would overwrite each
+    /// other's session.
+    pub fn add(&mut self, bot: Bot) -> Result<(), HeadjackError> {
+        let state_dir = bot.state_dir();
+        if self.bots.iter().any(|other| other.state_dir() == state_dir) {
+            return Err(HeadjackError::Config(format!(
+                "bot {} uses the same state directory as another bot: {}",
+                bot.name(),
+                state_dir.display()
+            )));
+        }
+        self.bots.push(bot);
+        Ok(())
+    }
+
+    /// Get the bots
+    pub fn bots(&self) -> &[Bot] {
+        &self.bots
+    }
+
+    /// Run all the bots until every one of them has stopped
+    /// A bot that fails doesn't stop the others. Returns the result of each bot, in the order
+    /// they were added. The logs of each bot are in a span with its name.
+    pub async fn run(&self) -> Vec<Result<(), HeadjackError>> {
+        let runs = self.bots.iter().map(|bot| {
+            let span = info_span!("bot", name = %bot.name());
+            async move {
+                let result = bot.run().await;
+                if let Err(e) = &result {
+                    error!("Bot stopped with an error: {:?}", e);
+                }
+                result
+            }
+            .instrument(span)
+        });
+        join_all(runs).await
+    }
+}
@@ -0,0 +1,1011 @@
+//! Messages received by the bot, and helpers for responding to them.
+//!
+//! The helpers only need the `Room` and the ID of the event they act on, so they can be used
+//! from the text handler as well as from commands.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::attachment::{AttachmentInfo, BaseFileInfo, BaseImageInfo, Thumbnail};
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::api::client::relations::get_relating_events_with_rel_type;
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::relation::{Annotation, RelationType, Thread};
+use matrix_sdk::ruma::events::room::message::{
+    AddMentions, AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+    FormattedBody, ForwardThread, ImageMessageEventContent, LocationMessageEventContent,
+    MessageFormat, MessageType, OriginalRoomMessageEvent, OriginalSyncRoomMessageEvent, Relation,
+    ReplacementMetadata, ReplyWithinThread, RoomMessageEventContent, VideoInfo,
+    VideoMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::{ImageInfo, MediaSource, ThumbnailInfo};
+use matrix_sdk::ruma::events::{
+    AnyMessageLikeEvent, AnyTimelineEvent, Mentions, MessageLikeEvent, MessageLikeEventContent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedUserId, UInt, UserId};
+use matrix_sdk::{Room, RoomState};
+use mime::Mime;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How often the typing notice is refreshed
+/// The notice times out on the homeserver after 4 seconds.
+const TYPING_REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How many of the events the bot sent are remembered per room
+/// Redactions of older events aren't reported by `on_own_message_redacted`.
+const TRACKED_SENT_EVENTS: usize = 100;
+
+/// How many events `purge_own_messages` searches for the bot's messages
+const PURGE_SEARCH_LIMIT: usize = 1000;
+
+/// A message received by the bot
+/// Passed to the text handler, command and location callbacks.
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// The user that sent the message
+    pub sender: OwnedUserId,
+    /// The text of the message, with the reply fallback and leading whitespace removed
+    pub body: String,
+    /// For commands, the arguments after the command, see `get_args`
+    /// Empty for the text handler.
+    pub args: String,
+    /// The full event of the message
+    pub event: OriginalSyncRoomMessageEvent,
+}
+
+impl Message {
+    pub(crate) fn new(event: OriginalSyncRoomMessageEvent, body: &str, args: &str) -> Self {
+        Message {
+            sender: event.sender.clone(),
+            body: body.to_string(),
+            args: args.to_string(),
+            event,
+        }
+    }
+
+    /// The ID of the message event
+    pub fn event_id(&self) -> &EventId {
+        &self.event.event_id
+    }
+
+    /// The formatted version of the body, with its format, if the sender's client sent one
+    /// Bots can use this to respond in the same format as the message.
+    pub fn formatted_body(&self) -> Option<&FormattedBody> {
+        match &self.event.content.msgtype {
+            MessageType::Text(text) => text.formatted.as_ref(),
+            MessageType::Notice(notice) => notice.formatted.as_ref(),
+            MessageType::Emote(emote) => emote.formatted.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The HTML version of the body, if the message was sent with HTML formatting
+    /// Includes the reply fallback of replies, unlike `body`.
+    pub fn html_body(&self) -> Option<&str> {
+        self.formatted_body()
+            .filter(|formatted| formatted.format == MessageFormat::Html)
+            .map(|formatted| formatted.body.as_str())
+    }
+
+    /// The geo URI of a shared location, e.g. "geo:51.5008,0.1247;u=35"
+    /// Returns None if the message isn't a location.
+    pub fn geo_uri(&self) -> Option<&str> {
+        match &self.event.content.msgtype {
+            MessageType::Location(location) => Some(&location.geo_uri),
+            _ => None,
+        }
+    }
+
+    /// The ID of the event this message is a reply to, if any
+    /// Messages in a thread only count as replies if they explicitly reply to a thread message.
+    pub fn in_reply_to(&self) -> Option<&EventId> {
+        match &self.event.content.relates_to {
+            Some(Relation::Reply { in_reply_to }) => Some(&in_reply_to.event_id),
+            Some(Relation::Thread(thread)) if !thread.is_falling_back => {
+                thread.in_reply_to.as_ref().map(|r| r.event_id.as_ref())
+            }
+            _ => None,
+        }
+    }
+
+    /// The ID of the root event of the thread this message is in, if any
+    pub fn thread_root(&self) -> Option<&EventId> {
+        match &self.event.content.relates_to {
+            Some(Relation::Thread(thread)) => Some(&thread.event_id),
+            _ => None,
+        }
+    }
+
+    /// Fetch the event this message is a reply to
+    /// Returns None if the message isn't a reply.
+    /// The event is fetched from the homeserver and decrypted if needed.
+    pub async fn replied_to(&self, room: &Room) -> Result<Option<AnyTimelineEvent>, HeadjackError> {
+        let Some(event_id) = self.in_reply_to() else {
+            return Ok(None);
+        };
+        let event = room.event(event_id).await?;
+        Ok(Some(event.event.deserialize()?))
+    }
+
+    /// The message as a full event, as needed by the reply functions of the SDK
+    fn full_event(&self, room: &Room) -> OriginalRoomMessageEvent {
+        self.event
+            .clone()
+            .into_full_event(room.room_id().to_owned())
+    }
+}
+
+/// Get the body of a message without the reply fallback
+/// Replies quote the original message in lines starting with "> ", followed by an empty line.
+pub(crate) fn strip_reply_fallback(event: &OriginalSyncRoomMessageEvent) -> &str {
+    let body = event.content.body();
+    if !matches!(event.content.relates_to, Some(Relation::Reply { .. })) {
+        return body;
+    }
+    let mut rest = body;
+    while let Some(line) = rest.strip_prefix("> ") {
+        rest = line.split_once('\n').map_or("", |(_, rest)| rest);
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+/// Deserialize an event fetched from the homeserver, decrypting it if possible
+/// Events that can't be decrypted are returned still encrypted.
+async fn decrypt_event(
+    room: &Room,
+    raw: Raw<AnyMessageLikeEvent>,
+) -> Result<AnyTimelineEvent, HeadjackError> {
+    if raw.get_field::<String>("type")?.as_deref() == Some("m.room.encrypted") {
+        if let Ok(event) = room.decrypt_event(raw.cast_ref()).await {
+            return Ok(event.event.deserialize()?);
+        }
+    }
+    Ok(raw.cast::<AnyTimelineEvent>().deserialize()?)
+}
+
+/// Check if the event is a message sent by the user that hasn't been redacted yet
+/// State events and redactions aren't counted, so purging doesn't undo e.g. the bot's join.
+fn is_purgeable(event: &Raw<AnyTimelineEvent>, user_id: &UserId) -> bool {
+    let sender = event.get_field::<OwnedUserId>("sender").ok().flatten();
+    let event_type = event.get_field::<String>("type").ok().flatten();
+    let is_state = event
+        .get_field::<Value>("state_key")
+        .ok()
+        .flatten()
+        .is_some();
+    let unsigned = event.get_field::<Value>("unsigned").ok().flatten();
+    let is_redacted = unsigned.is_some_and(|unsigned| unsigned.get("redacted_because").is_some());
+    sender.as_deref() == Some(user_id)
+        && event_type.as_deref() != Some("m.room.redaction")
+        && !is_state
+        && !is_redacted
+}
+
+/// Put the message in the thread, if there's a thread root
+fn thread_content(
+    thread_root: Option<&EventId>,
+    mut content: RoomMessageEventContent,
+) -> RoomMessageEventContent {
+    if let Some(root) = thread_root {
+        content.relates_to = Some(Relation::Thread(Thread::plain(
+            root.to_owned(),
+            root.to_owned(),
+        )));
+    }
+    content
+}
+
+/// Replace the `{{name}}` placeholders in the template with their values
+/// Placeholders without a value are left in place, so mistakes in templates are visible.
+pub fn render_template(template: &str, vars: &HashMap<String, String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after
+            .find("}}")
+            .and_then(|end| Some((vars.get(after[..end].trim())?, end)));
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Escape the characters that have a meaning in Markdown
+pub(crate) fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escape the characters that have a meaning in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An event from the history of a room, see `Bot::room_history`
+#[derive(Debug, Clone)]
+pub struct HistoryEvent {
+    /// The event, decrypted if possible
+    pub event: AnyTimelineEvent,
+    /// The number of users that reacted to the event with each key, e.g. "👍"
+    pub reactions: HashMap<String, usize>,
+}
+
+/// Shows the bot as typing in a room until it's dropped
+pub struct TypingGuard {
+    /// The room the bot is typing in
+    room: Room,
+    /// Keeps refreshing the typing notice
+    task: JoinHandle<()>,
+}
+
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+        let room = self.room.clone();
+        tokio::spawn(async move {
+            if let Err(e) = room.typing_notice(false).await {
+                warn!("Failed to clear the typing notice: {:?}", e);
+            }
+        });
+    }
+}
+
+impl Bot {
+    /// Send an event to the room, unless the bot is in read only mode
+    /// Everything the bot sends goes through here, so use this instead of `Room::send` to respect
+    /// the bot's settings. In read only mode the event is logged instead, and a made up event ID
+    /// is returned. With `require_encryption` set, sending to an unencrypted room fails.
+    pub async fn send<C>(&self, room: &Room, content: C) -> Result<OwnedEventId, HeadjackError>
+    where
+        C: MessageLikeEventContent + Debug,
+    {
+        // Held until the event is sent, so that the sends to a room are in order
+        let queued = self.send_queue(room).await;
+        let result = self.send_unqueued(room, content).await;
+        self.leave_send_queue(room, queued).await;
+        result
+    }
+
+    /// Send an event to the room without waiting for the send queue, see `send`
+    /// Only for callers that hold the queue already.
+    async fn send_unqueued<C>(&self, room: &Room, content: C) -> Result<OwnedEventId, HeadjackError>
+    where
+        C: MessageLikeEventContent + Debug,
+    {
+        // Only log the content itself if message bodies may be logged
+        let what = if self.config().log_message_bodies.unwrap_or(false) {
+            format!("{:?}", content)
+        } else {
+            content.event_type().to_string()
+        };
+        if !self.may_send(room, &what).await? {
+            return self.fake_event_id();
+        }
+        #[cfg(any(test, feature = "testing"))]
+        let (event_type, json) = (
+            content.event_type().to_string(),
+            serde_json::to_value(&content)?,
+        );
+        let event_id = room.send(content).await?.event_id;
+        self.track_sent(room, &event_id).await;
+        #[cfg(any(test, feature = "testing"))]
+        self.record_sent(room, &event_id, event_type, json).await;
+        Ok(event_id)
+    }
+
+    /// Wait for the earlier sends to the room to finish, if `ordered_sends` is set
+    /// The queue is a fair lock, so the sends go out in the order they were started.
+    async fn send_queue(&self, room: &Room) -> Option<OwnedMutexGuard<()>> {
+        if !self.config().ordered_sends.unwrap_or(false) {
+            return None;
+        }
+        let queue = self
+            .state
+            .lock()
+            .await
+            .send_queues
+            .entry(room.room_id().to_owned())
+            .or_default()
+            .clone();
+        Some(queue.lock_owned().await)
+    }
+
+    /// Let the next send to the room go, and drop the queue of the room if nothing waits on it
+    async fn leave_send_queue(&self, room: &Room, queued: Option<OwnedMutexGuard<()>>) {
+        let Some(guard) = queued else {
+            return;
+        };
+        drop(guard);
+        let mut state = self.state.lock().await;
+        // The sends waiting on the queue hold references to it
+        if state
+            .send_queues
+            .get(room.room_id())
+            .is_some_and(|queue| Arc::strong_count(queue) == 1)
+        {
+            state.send_queues.remove(room.room_id());
+        }
+    }
+
+    /// Remember an event the bot sent, see `on_own_message_redacted`
+    /// Only the most recent events in each room are kept.
+    async fn track_sent(&self, room: &Room, event_id: &EventId) {
+        let mut state = self.state.lock().await;
+        let sent = state
+            .sent_events
+            .entry(room.room_id().to_owned())
+            .or_default();
+        if sent.len() >= TRACKED_SENT_EVENTS {
+            sent.pop_front();
+        }
+        sent.push_back(event_id.to_owned());
+    }
+
+    /// Check if the bot may send to the room, logging what is skipped in read only mode
+    /// Returns an error if the room is unencrypted and `require_encryption` is set.
+    async fn may_send(&self, room: &Room, what: &str) -> Result<bool, HeadjackError> {
+        let require_encryption = self.config().require_encryption.unwrap_or(false);
+        if require_encryption && !self.is_room_encrypted(room).await {
+            warn!(
+                "Refusing to send {} to unencrypted room {}",
+                what,
+                room.room_id()
+            );
+            return Err(HeadjackError::Unencrypted(room.room_id().to_owned()));
+        }
+        if self.config().read_only.unwrap_or(false) {
+            info!("Read only, not sending {} to {}", what, room.room_id());
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Upload an image and send it to the room
+    /// The size, and the width and height if set in `info`, are included so clients can lay out
+    /// the image before downloading it. In encrypted rooms the image is encrypted.
+    pub async fn send_image(
+        &self,
+        room: &Room,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+        info: BaseImageInfo,
+        thumbnail: Option<Thumbnail>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.send_image_in_thread(room, None, filename, content_type, data, info, thumbnail)
+            .await
+    }
+
+    /// Upload an image and send it to a thread, or to the main timeline if there's no thread root
+    /// See `send_image` and `send_in_thread`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_image_in_thread(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+        info: BaseImageInfo,
+        thumbnail: Option<Thumbnail>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let info = BaseImageInfo {
+            size: info.size.or_else(|| UInt::new(data.len() as u64)),
+            ..info
+        };
+        let info = AttachmentInfo::Image(info);
+        self.send_attachment(
+            room,
+            thread_root,
+            filename,
+            content_type,
+            data,
+            info,
+            thumbnail,
+        )
+        .await
+    }
+
+    /// Upload a file and send it to the room
+    /// The message type is picked from the content type, so audio and video files show up as such.
+    /// In encrypted rooms the file is encrypted.
+    pub async fn send_file(
+        &self,
+        room: &Room,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.send_file_in_thread(room, None, filename, content_type, data)
+            .await
+    }
+
+    /// Upload a file and send it to a thread, or to the main timeline if there's no thread root
+    /// See `send_file` and `send_in_thread`.
+    pub async fn send_file_in_thread(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let info = AttachmentInfo::File(BaseFileInfo {
+            size: UInt::new(data.len() as u64),
+        });
+        self.send_attachment(room, thread_root, filename, content_type, data, info, None)
+            .await
+    }
+
+    /// Upload the attachment, encrypting it in encrypted rooms, and send it
+    /// `Room::send_attachment` can't send to threads, so the message is built here the same way.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_attachment(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+        info: AttachmentInfo,
+        thumbnail: Option<Thumbnail>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        // Don't upload anything in read only mode
+        let what = format!("{} ({}, {} bytes)", filename, content_type, data.len());
+        if !self.may_send(room, &what).await? {
+            return self.fake_event_id();
+        }
+        // Queued before the upload, so that the order is the order the sends were started in
+        let queued = self.send_queue(room).await;
+        let result = async {
+            let content = self
+                .attachment_content(room, filename, content_type, data, info, thumbnail)
+                .await?;
+            let content = thread_content(thread_root, content);
+            self.send_unqueued(room, content).await
+        }
+        .await;
+        self.leave_send_queue(room, queued).await;
+        result
+    }
+
+    /// Upload the attachment, encrypting it in encrypted rooms, and create the message for it
+    async fn attachment_content(
+        &self,
+        room: &Room,
+        filename: &str,
+        content_type: &Mime,
+        data: Vec<u8>,
+        info: AttachmentInfo,
+        thumbnail: Option<Thumbnail>,
+    ) -> Result<RoomMessageEventContent, HeadjackError> {
+        let encrypted = self.is_room_encrypted(room).await;
+        let (thumbnail_source, thumbnail_info) = match thumbnail {
+            Some(thumbnail) => {
+                let source = self
+                    .upload(encrypted, &thumbnail.content_type, thumbnail.data)
+                    .await?;
+                let mut info = thumbnail.info.map(ThumbnailInfo::from).unwrap_or_default();
+                info.mimetype = Some(thumbnail.content_type.to_string());
+                (Some(source), Some(Box::new(info)))
+            }
+            None => (None, None),
+        };
+        let source = self.upload(encrypted, content_type, data).await?;
+        let body = filename.to_string();
+        let mimetype = Some(content_type.to_string());
+        let msgtype = match content_type.type_() {
+            mime::IMAGE => {
+                let mut info = ImageInfo::from(info);
+                info.mimetype = mimetype;
+                info.thumbnail_source = thumbnail_source;
+                info.thumbnail_info = thumbnail_info;
+                MessageType::Image(ImageMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            mime::AUDIO => {
+                let mut info = AudioInfo::from(info);
+                info.mimetype = mimetype;
+                MessageType::Audio(AudioMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            mime::VIDEO => {
+                let mut info = VideoInfo::from(info);
+                info.mimetype = mimetype;
+                info.thumbnail_source = thumbnail_source;
+                info.thumbnail_info = thumbnail_info;
+                MessageType::Video(VideoMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+            _ => {
+                let mut info = FileInfo::from(info);
+                info.mimetype = mimetype;
+                info.thumbnail_source = thumbnail_source;
+                info.thumbnail_info = thumbnail_info;
+                MessageType::File(FileMessageEventContent::new(body, source).info(Box::new(info)))
+            }
+        };
+        Ok(RoomMessageEventContent::new(msgtype))
+    }
+
+    /// Upload media to the homeserver, encrypted if it's for an encrypted room
+    async fn upload(
+        &self,
+        encrypted: bool,
+        content_type: &Mime,
+        data: Vec<u8>,
+    ) -> Result<MediaSource, HeadjackError> {
+        let client = self.client()?;
+        if encrypted {
+            let file = client
+                .prepare_encrypted_file(content_type, &mut Cursor::new(data))
+                .await?;
+            return Ok(MediaSource::Encrypted(Box::new(file)));
+        }
+        let response = client.media().upload(content_type, data).await?;
+        Ok(MediaSource::Plain(response.content_uri))
+    }
+
+    /// Send a message to a user in a direct message room
+    /// Uses the existing DM room with the user if there is one, otherwise creates it.
+    pub async fn dm(
+        &self,
+        user_id: &UserId,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.dm_in_thread(user_id, None, content).await
+    }
+
+    /// Send a message to a user in a thread of the direct message room
+    /// See `dm` and `send_in_thread`.
+    pub async fn dm_in_thread(
+        &self,
+        user_id: &UserId,
+        thread_root: Option<&EventId>,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let room = match self.dm_room(user_id).await? {
+            Some(room) => room,
+            None if self.config().read_only.unwrap_or(false) => {
+                info!("Read only, not creating a DM room with {}", user_id);
+                return self.fake_event_id();
+            }
+            None => {
+                info!("Creating a DM room with {}", user_id);
+                let room = self.client()?.create_dm(user_id).await?;
+                self.state
+                    .lock()
+                    .await
+                    .dm_rooms
+                    .insert(user_id.to_owned(), room.room_id().to_owned());
+                room
+            }
+        };
+        self.send_in_thread(&room, thread_root, content).await
+    }
+
+    /// Find the existing DM room with the user, using the `m.direct` account data
+    /// The room is cached, so the lookup only happens once per user.
+    async fn dm_room(&self, user_id: &UserId) -> Result<Option<Room>, HeadjackError> {
+        let client = self.client()?;
+        let mut state = self.state.lock().await;
+        if let Some(room) = state
+            .dm_rooms
+            .get(user_id)
+            .and_then(|room_id| client.get_room(room_id))
+            .filter(|room| room.state() == RoomState::Joined)
+        {
+            return Ok(Some(room));
+        }
+        let room = client.get_dm_room(user_id);
+        match &room {
+            Some(room) => {
+                state
+                    .dm_rooms
+                    .insert(user_id.to_owned(), room.room_id().to_owned());
+            }
+            None => {
+                state.dm_rooms.remove(user_id);
+            }
+        }
+        Ok(room)
+    }
+
+    /// Check if the room is encrypted
+    /// Fetches the encryption state from the homeserver if it isn't known yet.
+    /// Errors are treated as unencrypted.
+    pub async fn is_room_encrypted(&self, room: &Room) -> bool {
+        room.is_encrypted().await.unwrap_or(false)
+    }
+
+    /// Redact an event, e.g. to delete a message the bot sent
+    /// Returns the ID of the redaction event.
+    pub async fn redact(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+        reason: Option<&str>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        if self.config().read_only.unwrap_or(false) {
+            info!(
+                "Read only, not redacting {} in {}",
+                event_id,
+                room.room_id()
+            );
+            return self.fake_event_id();
+        }
+        Ok(room.redact(event_id, reason, None).await?.event_id)
+    }
+
+    /// Redact the last messages the bot sent in the room
+    /// Searches back through the latest 1000 events of the room. Returns the number of messages
+    /// redacted, which is less than `count` if there weren't enough.
+    pub async fn purge_own_messages(
+        &self,
+        room: &Room,
+        count: usize,
+    ) -> Result<usize, HeadjackError> {
+        let user_id = self.user_id().ok_or(HeadjackError::NotLoggedIn)?;
+        let mut own_events = Vec::new();
+        let mut searched = 0;
+        let mut from = None;
+        while own_events.len() < count && searched < PURGE_SEARCH_LIMIT {
+            let mut options = MessagesOptions::backward();
+            options.from = from;
+            let messages = room.messages(options).await?;
+            searched += messages.chunk.len();
+            for event in messages.chunk {
+                if own_events.len() < count && is_purgeable(&event.event, &user_id) {
+                    if let Some(event_id) = event.event.get_field::<OwnedEventId>("event_id")? {
+                        own_events.push(event_id);
+                    }
+                }
+            }
+            match messages.end {
+                Some(end) => from = Some(end),
+                // Reached the start of the room
+                None => break,
+            }
+        }
+        for event_id in &own_events {
+            self.redact(room, event_id, None).await?;
+        }
+        info!(
+            "Redacted {} of the bot's messages in {}",
+            own_events.len(),
+            room.room_id()
+        );
+        Ok(own_events.len())
+    }
+
+    /// A random event ID, returned in place of real event IDs in read only mode
+    fn fake_event_id(&self) -> Result<OwnedEventId, HeadjackError> {
+        let user_id = self.user_id().ok_or(HeadjackError::NotLoggedIn)?;
+        Ok(EventId::new(user_id.server_name()))
+    }
+
+    /// Send a Markdown message from a template, replacing the `{{name}}` placeholders
+    /// The values are escaped, so they're shown as is rather than formatted as Markdown.
+    /// Placeholders without a value are left in place. See `render_template`.
+    pub async fn send_template(
+        &self,
+        room: &Room,
+        template: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.send_template_in_thread(room, None, template, vars)
+            .await
+    }
+
+    /// Send a Markdown message from a template to a thread, or to the main timeline if there's
+    /// no thread root. See `send_template` and `send_in_thread`.
+    pub async fn send_template_in_thread(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        template: &str,
+        vars: &HashMap<String, String>,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let escaped = vars
+            .iter()
+            .map(|(name, value)| (name.clone(), escape_markdown(value)))
+            .collect();
+        let text = render_template(template, &escaped);
+        let content = RoomMessageEventContent::text_markdown(text);
+        self.send_in_thread(room, thread_root, content).await
+    }
+
+    /// Send a location to the room
+    /// The geo URI is in the format "geo:latitude,longitude", the description is shown as the
+    /// text of the message.
+    pub async fn send_location(
+        &self,
+        room: &Room,
+        geo_uri: &str,
+        description: &str,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.send_location_in_thread(room, None, geo_uri, description)
+            .await
+    }
+
+    /// Send a location to a thread, or to the main timeline if there's no thread root
+    /// See `send_location` and `send_in_thread`.
+    pub async fn send_location_in_thread(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        geo_uri: &str,
+        description: &str,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content = RoomMessageEventContent::new(MessageType::Location(
+            LocationMessageEventContent::new(description.to_string(), geo_uri.to_string()),
+        ));
+        self.send_in_thread(room, thread_root, content).await
+    }
+
+    /// Send a message to a thread, or to the main timeline if there's no thread root
+    /// Pass `Message::thread_root` to respond where a message was sent. Clients that don't
+    /// support threads show the message as a reply to the root. The other send helpers have
+    /// `_in_thread` variants that work the same, replies stay in the thread of the message
+    /// they reply to, and reactions show up wherever the event they react to is.
+    pub async fn send_in_thread(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        self.send(room, thread_content(thread_root, content)).await
+    }
+
+    /// Get all the events in a thread, starting with the root, oldest first
+    /// The events are fetched from the homeserver, and decrypted if possible.
+    pub async fn thread_messages(
+        &self,
+        room: &Room,
+        thread_root: &EventId,
+    ) -> Result<Vec<AnyTimelineEvent>, HeadjackError> {
+        let mut events = vec![room.event(thread_root).await?.event.deserialize()?];
+        events.extend(
+            self.relating_events(room, thread_root, RelationType::Thread)
+                .await?,
+        );
+        Ok(events)
+    }
+
+    /// Get all the events that relate to an event with the given relation, oldest first
+    /// The events are fetched from the homeserver, and decrypted if possible.
+    pub(crate) async fn relating_events(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+        rel_type: RelationType,
+    ) -> Result<Vec<AnyTimelineEvent>, HeadjackError> {
+        let client = self.client()?;
+        let mut chunks = Vec::new();
+        let mut from = None;
+        loop {
+            let mut request = get_relating_events_with_rel_type::v1::Request::new(
+                room.room_id().to_owned(),
+                event_id.to_owned(),
+                rel_type.clone(),
+            );
+            request.from = from;
+            let response = client.send(request, None).await?;
+            chunks.extend(response.chunk);
+            match response.next_batch {
+                Some(next_batch) => from = Some(next_batch),
+                None => break,
+            }
+        }
+        let mut events = Vec::with_capacity(chunks.len());
+        // The homeserver returns the newest events first
+        for raw in chunks.into_iter().rev() {
+            events.push(decrypt_event(room, raw).await?);
+        }
+        Ok(events)
+    }
+
+    /// Get the latest events of the room, oldest first, with the reactions to each of them
+    /// Returns up to `limit` events, not counting the reactions themselves. The reactions are
+    /// tallied from the fetched history, which always includes every reaction to the returned
+    /// events as reactions come after the event they react to.
+    pub async fn room_history(
+        &self,
+        room: &Room,
+        limit: usize,
+    ) -> Result<Vec<HistoryEvent>, HeadjackError> {
+        let mut events = Vec::new();
+        let mut reactions: HashMap<(OwnedEventId, String), HashSet<OwnedUserId>> = HashMap::new();
+        let mut from = None;
+        while events.len() < limit {
+            let mut options = MessagesOptions::backward();
+            options.from = from;
+            let messages = room.messages(options).await?;
+            for event in messages.chunk {
+                let event = match event.event.deserialize() {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Skipping an invalid event in {}: {:?}", room.room_id(), e);
+                        continue;
+                    }
+                };
+                if let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Reaction(
+                    MessageLikeEvent::Original(reaction),
+                )) = &event
+                {
+                    let annotation = &reaction.content.relates_to;
+                    reactions
+                        .entry((annotation.event_id.clone(), annotation.key.clone()))
+                        .or_default()
+                        .insert(reaction.sender.clone());
+                } else if events.len() < limit {
+                    events.push(event);
+                }
+            }
+            match messages.end {
+                Some(end) => from = Some(end),
+                // Reached the start of the room
+                None => break,
+            }
+        }
+        let mut tallies: HashMap<OwnedEventId, HashMap<String, usize>> = HashMap::new();
+        for ((event_id, key), senders) in reactions {
+            tallies
+                .entry(event_id)
+                .or_default()
+                .insert(key, senders.len());
+        }
+        let mut history: Vec<HistoryEvent> = events
+            .into_iter()
+            .map(|event| HistoryEvent {
+                reactions: tallies.remove(event.event_id()).unwrap_or_default(),
+                event,
+            })
+            .collect();
+        history.reverse();
+        Ok(history)
+    }
+
+    /// Show the bot as typing in the room until the returned guard is dropped
+    /// Useful for showing activity while a slow response is being generated.
+    pub fn typing(&self, room: &Room) -> TypingGuard {
+        let task = {
+            let room = room.clone();
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = room.typing_notice(true).await {
+                        warn!("Failed to send the typing notice: {:?}", e);
+                    }
+                    tokio::time::sleep(TYPING_REFRESH_INTERVAL).await;
+                }
+            })
+        };
+        TypingGuard {
+            room: room.clone(),
+            task,
+        }
+    }
+
+    /// React to an event with the given key, usually an emoji
+    /// Returns the ID of the reaction event. Reactions to events in a thread show up in the
+    /// thread, so there's no thread variant.
+    pub async fn react(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+        key: &str,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content =
+            ReactionEventContent::new(Annotation::new(event_id.to_owned(), key.to_string()));
+        self.send(room, content).await
+    }
+
+    /// Replace the content of a message the bot sent previously
+    /// Returns the ID of the edit event.
+    pub async fn edit(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content =
+            content.make_replacement(ReplacementMetadata::new(event_id.to_owned(), None), None);
+        self.send(room, content).await
+    }
+
+    /// Send a reply to a message
+    /// If the message is in a thread, the reply is sent in the same thread, otherwise in the main
+    /// timeline. Use `reply_in_thread` to start a thread from the message.
+    pub async fn reply(
+        &self,
+        room: &Room,
+        message: &Message,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content = content.make_reply_to(
+            &message.full_event(room),
+            ForwardThread::Yes,
+            AddMentions::Yes,
+        );
+        self.send(room, content).await
+    }
+
+    /// Send a reply to a message that also mentions a user, so that they get notified
+    /// The mention is set in `m.mentions`, use `mention_pill` to also mention them in the text.
+    pub async fn reply_mentioning(
+        &self,
+        room: &Room,
+        message: &Message,
+        user_id: &UserId,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content = content
+            .make_reply_to(
+                &message.full_event(room),
+                ForwardThread::Yes,
+                AddMentions::Yes,
+            )
+            .add_mentions(Mentions::with_user_ids([user_id.to_owned()]));
+        self.send(room, content).await
+    }
+
+    /// Create a mention of a user, as the plain text and the HTML pill clients display
+    /// Use both in `RoomMessageEventContent::text_html`. Only the text doesn't notify the user,
+    /// see `reply_mentioning`.
+    pub fn mention_pill(user_id: &UserId, display_name: &str) -> (String, String) {
+        let html = format!(
+            "<a href=\"{}\">{}</a>",
+            user_id.matrix_to_uri(),
+            escape_html(display_name)
+        );
+        (display_name.to_string(), html)
+    }
+
+    /// Send a reply to a message in a thread
+    /// Continues the thread the message is in, or starts a new thread from the message.
+    pub async fn reply_in_thread(
+        &self,
+        room: &Room,
+        message: &Message,
+        content: RoomMessageEventContent,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content = content.make_for_thread(
+            &message.full_event(room),
+            ReplyWithinThread::Yes,
+            AddMentions::Yes,
+        );
+        self.send(room, content).await
+    }
+}
@@ -0,0 +1,172 @@
+//! Long messages split into pages, that users page through by reacting.
+//!
+//! The bot sends the first page and reacts to it with the previous and next page keys. When a
+//! user reacts with one of them, the message is edited to show the other page.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::events::reaction::OriginalSyncReactionEvent;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
+use matrix_sdk::Room;
+use tracing::error;
+
+/// The reaction key that shows the previous page
+pub const PREVIOUS_PAGE: &str = "\u{25c0}\u{fe0f}";
+
+/// The reaction key that shows the next page
+pub const NEXT_PAGE: &str = "\u{25b6}\u{fe0f}";
+
+/// How many paginated messages can be paged through
+/// Older messages stay on the page they were on.
+const TRACKED_PAGINATED_MESSAGES: usize = 100;
+
+/// A message with several pages of markdown
+#[derive(Debug, Clone)]
+pub struct PaginatedMessage {
+    /// The pages, never empty
+    pages: Vec<String>,
+}
+
+impl PaginatedMessage {
+    /// Create a message with the given pages
+    /// Without any pages the message has a single empty page.
+    pub fn new(pages: Vec<String>) -> Self {
+        if pages.is_empty() {
+            return PaginatedMessage {
+                pages: vec![String::new()],
+            };
+        }
+        PaginatedMessage { pages }
+    }
+
+    /// Split lines into pages of at most `page_size` lines, each starting with the header
+    pub fn from_lines<I, S>(header: &str, lines: I, page_size: usize) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let lines: Vec<S> = lines.into_iter().collect();
+        let pages = lines
+            .chunks(page_size.max(1))
+            .map(|chunk| {
+                let mut page = header.to_string();
+                for line in chunk {
+                    page.push('\n');
+                    page.push_str(line.as_ref());
+                }
+                page
+            })
+            .collect();
+        PaginatedMessage::new(pages)
+    }
+
+    /// Get the pages
+    pub fn pages(&self) -> &[String] {
+        &self.pages
+    }
+}
+
+/// A paginated message the bot sent, and the page it shows
+#[derive(Debug)]
+pub(crate) struct PaginatedState {
+    /// The ID of the sent message
+    event_id: OwnedEventId,
+    /// The message
+    message: PaginatedMessage,
+    /// The index of the page that is shown
+    page: usize,
+}
+
+impl Bot {
+    /// Send a message that users can page through by reacting to it
+    /// A message with a single page is sent as a regular message. Returns the ID of the message.
+    pub async fn send_paginated(
+        &self,
+        room: &Room,
+        thread_root: Option<&EventId>,
+        message: PaginatedMessage,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let content = self.page_content(&message, 0);
+        let event_id = self.send_in_thread(room, thread_root, content).await?;
+        if message.pages.len() == 1 {
+            return Ok(event_id);
+        }
+        {
+            let mut state = self.state.lock().await;
+            if state.paginated.len() >= TRACKED_PAGINATED_MESSAGES {
+                state.paginated.pop_front();
+            }
+            state.paginated.push_back(PaginatedState {
+                event_id: event_id.clone(),
+                message,
+                page: 0,
+            });
+        }
+        self.react(room, &event_id, PREVIOUS_PAGE).await?;
+        self.react(room, &event_id, NEXT_PAGE).await?;
+        Ok(event_id)
+    }
+
+    /// The content showing a page of the message, with the page number if there are several
+    fn page_content(&self, message: &PaginatedMessage, page: usize) -> RoomMessageEventContent {
+        let mut text = message.pages[page].clone();
+        if message.pages.len() > 1 {
+            let footer = self
+                .strings()
+                .page_footer
+                .replace("{page}", &(page + 1).to_string())
+                .replace("{pages}", &message.pages.len().to_string());
+            text.push_str("\n\n");
+            text.push_str(&footer);
+        }
+        RoomMessageEventContent::text_markdown(text)
+    }
+
+    /// Add the handler that turns the pages of paginated messages
+    pub(crate) fn register_pagination_handler(&self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let bot = self.clone();
+        let username = self.full_name()?;
+        client.add_event_handler(
+            move |event: OriginalSyncReactionEvent, room: Room| async move {
+                let step: isize = match event.content.relates_to.key.as_str() {
+                    PREVIOUS_PAGE => -1,
+                    NEXT_PAGE => 1,
+                    _ => return,
+                };
+                // The bot adds the keys itself
+                if event.sender == username {
+                    return;
+                }
+                if !bot
+                    .is_allowed_in_room(&room, event.sender.as_str(), &username)
+                    .await
+                {
+                    return;
+                }
+                let target = &event.content.relates_to.event_id;
+                let content = {
+                    let mut state = bot.state.lock().await;
+                    let Some(paginated) = state
+                        .paginated
+                        .iter_mut()
+                        .find(|paginated| &paginated.event_id == target)
+                    else {
+                        return;
+                    };
+                    let pages = paginated.message.pages.len() as isize;
+                    let page = (paginated.page as isize + step).clamp(0, pages - 1) as usize;
+                    if page == paginated.page {
+                        return;
+                    }
+                    paginated.page = page;
+                    bot.page_content(&paginated.message, page)
+                };
+                if let Err(e) = bot.edit(&room, target, content).await {
+                    error!("Error turning the page of {}: {:?}", target, e);
+                }
+            },
+        );
+        Ok(())
+    }
+}
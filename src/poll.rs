@@ -0,0 +1,176 @@
+//! Polls sent by the bot, and counting their votes.
+//!
+//! Polls use the unstable MSC3381 events, which most clients support. Clients that don't show the
+//! poll get a text fallback that lists the options with a number emoji each, so their users vote
+//! by reacting with the emoji of an option. `Bot::poll_results` counts both kinds of votes.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::events::poll::start::PollKind;
+use matrix_sdk::ruma::events::poll::unstable_start::{
+    NewUnstablePollStartEventContent, UnstablePollAnswer, UnstablePollAnswers,
+    UnstablePollStartContentBlock,
+};
+use matrix_sdk::ruma::events::poll::{compile_unstable_poll_results, PollResponseData};
+use matrix_sdk::ruma::events::relation::RelationType;
+use matrix_sdk::ruma::events::{AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent};
+use matrix_sdk::ruma::{EventId, OwnedEventId, UserId};
+use matrix_sdk::Room;
+use std::collections::HashSet;
+
+/// The reaction keys used to vote in the text fallback, one per option
+const OPTION_EMOJIS: [&str; 10] = [
+    "1\u{fe0f}\u{20e3}",
+    "2\u{fe0f}\u{20e3}",
+    "3\u{fe0f}\u{20e3}",
+    "4\u{fe0f}\u{20e3}",
+    "5\u{fe0f}\u{20e3}",
+    "6\u{fe0f}\u{20e3}",
+    "7\u{fe0f}\u{20e3}",
+    "8\u{fe0f}\u{20e3}",
+    "9\u{fe0f}\u{20e3}",
+    "\u{1f51f}",
+];
+
+/// The votes for one option of a poll
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PollOption {
+    /// The text of the option
+    pub text: String,
+    /// The number of users that voted for the option
+    pub votes: usize,
+}
+
+/// The option a reaction votes for, if its key is one of the number emojis
+/// Some clients send the keycaps without the variation selector, so it's optional.
+fn option_index(key: &str) -> Option<usize> {
+    let key = key.replace('\u{fe0f}', "");
+    OPTION_EMOJIS
+        .iter()
+        .position(|emoji| emoji.replace('\u{fe0f}', "") == key)
+}
+
+impl Bot {
+    /// Send a single choice poll to the room
+    /// Takes 2 to 10 options, the most the reaction fallback has emojis for. Returns the ID of
+    /// the poll, to get its results with `Bot::poll_results`.
+    pub async fn send_poll(
+        &self,
+        room: &Room,
+        question: &str,
+        options: &[&str],
+    ) -> Result<OwnedEventId, HeadjackError> {
+        if options.len() < 2 || options.len() > OPTION_EMOJIS.len() {
+            return Err(HeadjackError::Poll(format!(
+                "a poll needs 2 to {} options, got {}",
+                OPTION_EMOJIS.len(),
+                options.len()
+            )));
+        }
+        let mut fallback = question.to_string();
+        let mut answers = Vec::with_capacity(options.len());
+        for (i, (option, emoji)) in options.iter().zip(OPTION_EMOJIS).enumerate() {
+            fallback.push_str(&format!("\n{} {}", emoji, option));
+            answers.push(UnstablePollAnswer::new((i + 1).to_string(), *option));
+        }
+        let answers = UnstablePollAnswers::try_from(answers)
+            .map_err(|e| HeadjackError::Poll(e.to_string()))?;
+        let mut block = UnstablePollStartContentBlock::new(question, answers);
+        // The results can be read at any time with poll_results, so clients may show them too
+        block.kind = PollKind::Disclosed;
+        let content = NewUnstablePollStartEventContent::plain_text(fallback, block);
+        self.send(room, content).await
+    }
+
+    /// Count the votes of a poll, in the order of its options
+    /// Each user has one vote. A poll response takes precedence over reactions, otherwise the
+    /// user's oldest number emoji reaction counts. Votes sent after the poll was ended by its
+    /// sender are ignored.
+    pub async fn poll_results(
+        &self,
+        room: &Room,
+        poll_id: &EventId,
+    ) -> Result<Vec<PollOption>, HeadjackError> {
+        let poll = match room.event(poll_id).await?.event.deserialize()? {
+            AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::UnstablePollStart(
+                MessageLikeEvent::Original(poll),
+            )) => poll,
+            _ => {
+                return Err(HeadjackError::Poll(format!(
+                    "{} is not a poll in {}",
+                    poll_id,
+                    room.room_id()
+                )))
+            }
+        };
+        let block = poll.content.poll_start();
+
+        let mut responses = Vec::new();
+        let mut end = None;
+        for event in self
+            .relating_events(room, poll_id, RelationType::Reference)
+            .await?
+        {
+            match event {
+                AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::UnstablePollResponse(
+                    MessageLikeEvent::Original(response),
+                )) => responses.push(response),
+                AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::UnstablePollEnd(
+                    MessageLikeEvent::Original(poll_end),
+                )) if poll_end.sender == poll.sender && end.is_none() => {
+                    end = Some(poll_end.origin_server_ts)
+                }
+                _ => {}
+            }
+        }
+        let results = compile_unstable_poll_results(
+            block,
+            responses.iter().map(|response| PollResponseData {
+                sender: &response.sender,
+                origin_server_ts: response.origin_server_ts,
+                selections: &response.content.poll_response.answers,
+            }),
+            end,
+        );
+
+        let mut options: Vec<PollOption> = block
+            .answers
+            .iter()
+            .map(|answer| PollOption {
+                text: answer.text.clone(),
+                votes: results
+                    .get(answer.id.as_str())
+                    .map_or(0, |users| users.len()),
+            })
+            .collect();
+
+        // Fallback votes, from users without a poll response
+        let mut voted: HashSet<&UserId> = responses
+            .iter()
+            .map(|response| response.sender.as_ref())
+            .collect();
+        let mut reactions: Vec<_> = self
+            .relating_events(room, poll_id, RelationType::Annotation)
+            .await?
+            .into_iter()
+            .filter_map(|event| match event {
+                AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Reaction(
+                    MessageLikeEvent::Original(reaction),
+                )) => Some(reaction),
+                _ => None,
+            })
+            .filter(|reaction| end.map_or(true, |end| reaction.origin_server_ts <= end))
+            .collect();
+        reactions.sort_by_key(|reaction| reaction.origin_server_ts);
+        for reaction in &reactions {
+            let Some(option) = option_index(&reaction.content.relates_to.key)
+                .and_then(|index| options.get_mut(index))
+            else {
+                continue;
+            };
+            if voted.insert(&*reaction.sender) {
+                option.votes += 1;
+            }
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,192 @@
+//! Passing events to the handlers that didn't arrive through the sync.
+//!
+//! The messages handlers get the events of each sync from matrix-sdk, which can't be given other
+//! events. So the handlers are also kept here, to pass them the messages that `process_backfill`
+//! paginated, and the events that could only be decrypted after retrying.
+
+use crate::{Bot, HeadjackError};
+use futures_util::future::{join_all, BoxFuture};
+use futures_util::FutureExt;
+use matrix_sdk::event_handler::SyncEvent;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::events::reaction::OriginalSyncReactionEvent;
+use matrix_sdk::ruma::events::room::message::OriginalSyncRoomMessageEvent;
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{OwnedRoomId, UInt};
+use matrix_sdk::sync::SyncResponse;
+use matrix_sdk::Room;
+use serde::de::DeserializeOwned;
+use std::future::{ready, Future};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+/// How many messages are requested at once when paginating the backfill
+const BACKFILL_BATCH: u32 = 100;
+
+/// A handler that gets the events it handles, and ignores the others
+type ReplayHandler =
+    Arc<dyn Fn(AnySyncTimelineEvent, Room) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The message handlers, to pass them events that didn't arrive through the sync
+#[derive(Clone, Default)]
+pub(crate) struct ReplayHandlers(Arc<RwLock<Vec<ReplayHandler>>>);
+
+impl std::fmt::Debug for ReplayHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReplayHandlers")
+    }
+}
+
+/// The messages that arrived while the bot was offline, oldest first, see `process_backfill`
+pub(crate) type Backfill = Vec<(OwnedRoomId, Vec<Raw<AnySyncTimelineEvent>>)>;
+
+impl Bot {
+    /// Add an event handler, that also gets the events passed to `replay_event`
+    /// `select` picks the events the handler is for out of the replayed events.
+    pub(crate) fn add_replayable_handler<Ev, H, Fut>(
+        &self,
+        select: fn(AnySyncTimelineEvent) -> Option<Ev>,
+        handler: H,
+    ) -> Result<(), HeadjackError>
+    where
+        Ev: SyncEvent + DeserializeOwned + Send + 'static,
+        H: FnOnce(Ev, Room) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.client()?.add_event_handler(handler.clone());
+        let replay: ReplayHandler = Arc::new(move |event, room| match select(event) {
+            Some(event) => handler.clone()(event, room).boxed(),
+            None => ready(()).boxed(),
+        });
+        self.replay_handlers
+            .0
+            .write()
+            .expect("replay handlers lock poisoned")
+            .push(replay);
+        Ok(())
+    }
+
+    /// Pass an event to the message handlers, as if it arrived in a sync
+    pub(crate) async fn replay_event(&self, event: AnySyncTimelineEvent, room: &Room) {
+        let handlers = self
+            .replay_handlers
+            .0
+            .read()
+            .expect("replay handlers lock poisoned")
+            .clone();
+        join_all(
+            handlers
+                .iter()
+                .map(|handler| handler(event.clone(), room.clone())),
+        )
+        .await;
+    }
+
+    /// Collect the messages of the catch-up sync since `since`, see `process_backfill`
+    /// The sync only returns the latest messages of rooms with many new ones, the older ones
+    /// are paginated back to `since`.
+    pub(crate) async fn collect_backfill(
+        &self,
+        response: &SyncResponse,
+        since: &str,
+    ) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let mut backfill = Backfill::new();
+        for (room_id, update) in &response.rooms.join {
+            let Some(room) = client.get_room(room_id) else {
+                continue;
+            };
+            let mut events = match (&update.timeline.prev_batch, update.timeline.limited) {
+                (Some(prev_batch), true) => paginate_back(&room, prev_batch, since).await?,
+                _ => Vec::new(),
+            };
+            events.extend(update.timeline.events.iter().map(|e| e.event.clone()));
+            if !events.is_empty() {
+                backfill.push((room_id.clone(), events));
+            }
+        }
+        let count: usize = backfill.iter().map(|(_, events)| events.len()).sum();
+        info!(
+            "Collected {count} events since the last run, in {} rooms",
+            backfill.len()
+        );
+        self.state.lock().await.backfill = backfill;
+        Ok(())
+    }
+
+    /// Pass the messages collected by `collect_backfill` to the handlers, oldest first
+    pub(crate) async fn replay_backfill(&self) -> Result<(), HeadjackError> {
+        let backfill = std::mem::take(&mut self.state.lock().await.backfill);
+        let client = self.client()?;
+        for (room_id, events) in backfill {
+            let Some(room) = client.get_room(&room_id) else {
+                continue;
+            };
+            for raw in events {
+                match raw.deserialize() {
+                    Ok(event) => self.replay_event(event, &room).await,
+                    Err(e) => warn!("Skipping an invalid event in {}: {:?}", room_id, e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Paginate the messages of the room from `from` back to `to`, returning them oldest first
+async fn paginate_back(
+    room: &Room,
+    from: &str,
+    to: &str,
+) -> Result<Vec<Raw<AnySyncTimelineEvent>>, HeadjackError> {
+    let mut events = Vec::new();
+    let mut from = from.to_owned();
+    loop {
+        let mut options = MessagesOptions::backward().from(from.as_str());
+        options.to = Some(to.to_owned());
+        options.limit = UInt::from(BACKFILL_BATCH);
+        let messages = room.messages(options).await?;
+        if messages.chunk.is_empty() {
+            break;
+        }
+        events.extend(messages.chunk.into_iter().map(|event| event.event.cast()));
+        match messages.end {
+            Some(end) => from = end,
+            // Reached the start of the room
+            None => break,
+        }
+    }
+    events.reverse();
+    Ok(events)
+}
+
+/// Select the room messages that aren't redacted
+pub(crate) fn room_message(event: AnySyncTimelineEvent) -> Option<OriginalSyncRoomMessageEvent> {
+    match event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(event),
+        )) => Some(event),
+        _ => None,
+    }
+}
+
+/// Select the message-like events
+pub(crate) fn message_like(event: AnySyncTimelineEvent) -> Option<AnySyncMessageLikeEvent> {
+    match event {
+        AnySyncTimelineEvent::MessageLike(event) => Some(event),
+        _ => None,
+    }
+}
+
+/// Select the reactions that aren't redacted
+pub(crate) fn reaction(event: AnySyncTimelineEvent) -> Option<OriginalSyncReactionEvent> {
+    match event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+            SyncMessageLikeEvent::Original(event),
+        )) => Some(event),
+        _ => None,
+    }
+}
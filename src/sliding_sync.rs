@@ -0,0 +1,164 @@
+//! Syncing through a sliding sync proxy, see `BotConfig::sliding_sync`.
+
+use crate::{describe_sync_error, format_duration, is_fatal_sync_error, Bot, HeadjackError};
+use futures_util::{pin_mut, StreamExt};
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::{SlidingSync, SlidingSyncList, SlidingSyncListLoadingState, SlidingSyncMode};
+use std::future::ready;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::{error, info, trace, warn};
+
+/// The ID of the sliding sync connection, at most 16 characters
+const CONNECTION_ID: &str = "headjack";
+
+/// The list with every room of the bot
+const ALL_ROOMS: &str = "all_rooms";
+
+/// How many rooms are added to the list with each request
+const BATCH_SIZE: u32 = 100;
+
+/// The messages fetched per room while loading the rooms
+/// They're skipped by `sync()`, so only the latest is needed.
+const INITIAL_TIMELINE_LIMIT: u32 = 1;
+
+/// The messages fetched per room once running, enough for bursts between two requests
+const TIMELINE_LIMIT: u32 = 20;
+
+/// How long to wait before restarting the sync after an error
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+impl Bot {
+    /// Sync until all the rooms are loaded, skipping their messages
+    pub(crate) async fn sliding_sync_initial(&self) -> Result<(), HeadjackError> {
+        let sliding_sync = self.sliding_sync().await?;
+
+        info!("Starting the initial sliding sync");
+        let started = Instant::now();
+        loop {
+            let stream = sliding_sync.sync();
+            pin_mut!(stream);
+            while let Some(update) = stream.next().await {
+                match update {
+                    Ok(_) => {
+                        if let Some(rooms) = loaded_rooms(&sliding_sync).await {
+                            info!(
+                                "Initial sync done in {}, in {rooms} rooms",
+                                format_duration(started.elapsed())
+                            );
+                            self.initial_sync_complete.store(true, Ordering::Relaxed);
+                            return Ok(());
+                        }
+                    }
+                    Err(error) if is_fatal_sync_error(&error) => {
+                        return Err(self.sync_error(error).await);
+                    }
+                    Err(error) => {
+                        trace!("Initial sync error: {error:?}");
+                        error!(
+                            "The initial sync failed, trying again: {}",
+                            describe_sync_error(&error)
+                        );
+                        // An error ends the stream
+                        break;
+                    }
+                }
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    /// Sync until a fatal error happens, passing the events to the handlers
+    pub(crate) async fn run_sliding_sync(&self) -> Result<(), HeadjackError> {
+        let sliding_sync = self.sliding_sync().await?;
+        sliding_sync
+            .on_list(ALL_ROOMS, |list| {
+                list.set_timeline_limit(Some(TIMELINE_LIMIT));
+                ready(())
+            })
+            .await;
+
+        loop {
+            let stream = sliding_sync.sync();
+            pin_mut!(stream);
+            while let Some(update) = stream.next().await {
+                match update {
+                    Ok(_) => {
+                        if !self.initial_sync_complete.load(Ordering::Relaxed) {
+                            if let Some(rooms) = loaded_rooms(&sliding_sync).await {
+                                info!("Initial sync done, in {rooms} rooms");
+                                self.initial_sync_complete.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                    Err(error) if is_fatal_sync_error(&error) => {
+                        trace!("Sync error: {error:?}");
+                        error!(
+                            "Fatal sync error, stopping: {}",
+                            describe_sync_error(&error)
+                        );
+                        return Err(self.sync_error(error).await);
+                    }
+                    Err(error) => {
+                        trace!("Sync error: {error:?}");
+                        warn!("Sync failed, retrying: {}", describe_sync_error(&error));
+                        break;
+                    }
+                }
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    /// The sliding sync of the bot, created on first use
+    /// It's kept so that `run()` continues from where `sync()` stopped.
+    async fn sliding_sync(&self) -> Result<SlidingSync, HeadjackError> {
+        if let Some(sliding_sync) = &self.state.lock().await.sliding_sync {
+            return Ok(sliding_sync.clone());
+        }
+
+        let list = SlidingSyncList::builder(ALL_ROOMS)
+            .sync_mode(SlidingSyncMode::new_growing(BATCH_SIZE))
+            .timeline_limit(INITIAL_TIMELINE_LIMIT)
+            .required_state(vec![
+                (StateEventType::RoomCreate, String::new()),
+                (StateEventType::RoomEncryption, String::new()),
+                (StateEventType::RoomName, String::new()),
+                (StateEventType::RoomPowerLevels, String::new()),
+                (StateEventType::RoomMember, "$ME".to_string()),
+                (StateEventType::RoomMember, "$LAZY".to_string()),
+            ]);
+        let mut builder = self
+            .client()?
+            .sliding_sync(CONNECTION_ID)?
+            .add_list(list)
+            .with_all_extensions();
+        if self.config().ignore_ephemeral.unwrap_or(false) {
+            builder = builder
+                .without_typing_extension()
+                .without_receipt_extension();
+        }
+        let sliding_sync = builder.build().await?;
+
+        Ok(self
+            .state
+            .lock()
+            .await
+            .sliding_sync
+            .get_or_insert(sliding_sync)
+            .clone())
+    }
+}
+
+/// The number of rooms, once they're all loaded
+async fn loaded_rooms(sliding_sync: &SlidingSync) -> Option<u32> {
+    sliding_sync
+        .on_list(ALL_ROOMS, |list| {
+            ready(
+                (list.state() == SlidingSyncListLoadingState::FullyLoaded)
+                    .then(|| list.maximum_number_of_rooms().unwrap_or(0)),
+            )
+        })
+        .await
+        .flatten()
+}
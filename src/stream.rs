@@ -0,0 +1,142 @@
+//! Streaming responses, for bots that generate their output incrementally.
+//!
+//! A `ResponseStream` sends a single message and edits it in place as more text arrives.
+//! Homeservers rate limit edits, so deltas are coalesced and the message is edited at most
+//! once per interval. Long responses are split over multiple messages.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
+use matrix_sdk::Room;
+use std::time::{Duration, Instant};
+
+/// The minimum time between edits of the message
+const EDIT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The maximum length of a single message, in bytes
+/// Events are limited to 64KiB in total, this leaves room for the formatted body.
+const MAX_MESSAGE_LENGTH: usize = 16_000;
+
+/// A response that is streamed into a single message by editing it
+pub struct ResponseStream {
+    /// The bot sending the response
+    bot: Bot,
+    /// The room to respond in
+    room: Room,
+    /// The thread to respond in, if any
+    thread_root: Option<OwnedEventId>,
+    /// The message being edited, if it has been sent
+    event_id: Option<OwnedEventId>,
+    /// The full text of the current message
+    text: String,
+    /// Whether the text has changed since the last edit
+    dirty: bool,
+    /// The time of the last edit
+    last_edit: Instant,
+    /// The minimum time between edits
+    interval: Duration,
+}
+
+impl Bot {
+    /// Start a streamed response in the room
+    /// Nothing is sent until the first `push`.
+    pub fn stream_response(&self, room: &Room) -> ResponseStream {
+        ResponseStream::new(self.clone(), room.clone())
+    }
+}
+
+impl ResponseStream {
+    /// Create a new stream for the room
+    fn new(bot: Bot, room: Room) -> Self {
+        ResponseStream {
+            bot,
+            room,
+            thread_root: None,
+            event_id: None,
+            text: String::new(),
+            dirty: false,
+            last_edit: Instant::now(),
+            interval: EDIT_INTERVAL,
+        }
+    }
+
+    /// Set the minimum time between edits
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Send the response in a thread instead of the main timeline
+    pub fn in_thread(mut self, thread_root: &EventId) -> Self {
+        self.thread_root = Some(thread_root.to_owned());
+        self
+    }
+
+    /// Append text to the response
+    /// The message is only edited if enough time has passed since the last edit.
+    pub async fn push(&mut self, delta: &str) -> Result<(), HeadjackError> {
+        let mut delta = delta;
+        loop {
+            // Start a new message if this one would get too long
+            if self.text.len() + delta.len() > MAX_MESSAGE_LENGTH && !self.text.is_empty() {
+                self.flush().await?;
+                self.event_id = None;
+                self.text.clear();
+            }
+            // A delta too long for a single message is split over several
+            let split = char_boundary(delta, MAX_MESSAGE_LENGTH);
+            self.text.push_str(&delta[..split]);
+            self.dirty = true;
+            delta = &delta[split..];
+            if delta.is_empty() {
+                break;
+            }
+        }
+        if self.event_id.is_none() || self.last_edit.elapsed() >= self.interval {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send any remaining text and finish the response
+    /// Returns the ID of the last message sent, if any.
+    pub async fn finish(mut self) -> Result<Option<OwnedEventId>, HeadjackError> {
+        self.flush().await?;
+        Ok(self.event_id)
+    }
+
+    /// Send or edit the message with the current text
+    async fn flush(&mut self) -> Result<(), HeadjackError> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let content = RoomMessageEventContent::text_markdown(&self.text);
+        match &self.event_id {
+            None => {
+                let thread_root = self.thread_root.as_deref();
+                let event_id = self
+                    .bot
+                    .send_in_thread(&self.room, thread_root, content)
+                    .await?;
+                self.event_id = Some(event_id);
+            }
+            Some(event_id) => {
+                self.bot.edit(&self.room, event_id, content).await?;
+            }
+        }
+        self.dirty = false;
+        self.last_edit = Instant::now();
+        Ok(())
+    }
+}
+
+/// The largest index of at most `max` that is on a char boundary of the text
+fn char_boundary(text: &str, max: usize) -> usize {
+    if text.len() <= max {
+        return text.len();
+    }
+    (0..=max)
+        .rev()
+        .find(|&index| text.is_char_boundary(index))
+        .unwrap_or(0)
+}
@@ -0,0 +1,97 @@
+//! User facing strings of the builtin commands, so that they can be translated.
+
+use serde::Deserialize;
+
+/// The strings used by the builtin commands
+/// Placeholders in braces, like `{count}`, are replaced with the actual values.
+/// Missing strings keep their default when read from a config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Strings {
+    /// Help text of the help command
+    pub help_description: String,
+    /// Shown above the list of commands in the help
+    pub help_header: String,
+    /// Sent when a user isn't allowed to use a command
+    pub not_allowed: String,
+    /// Reply to a command that took longer than `command_timeout`
+    /// Set it to an empty string to not reply.
+    pub command_timed_out: String,
+    /// Reply to a command sent while `max_concurrent_commands` are running
+    /// Set it to an empty string to not reply.
+    pub busy: String,
+    /// Reply to the first command a user sends too soon after another, see `command_burst_window`
+    /// Set it to an empty string to not reply.
+    pub one_at_a_time: String,
+    /// Help text of the ping command
+    pub ping_description: String,
+    /// Response to the ping command, with the `{latency}` in milliseconds
+    pub ping_response: String,
+    /// Appended to the ping response once the bot is running, with the `{uptime}`
+    pub ping_uptime: String,
+    /// Help text of the rooms command
+    pub rooms_description: String,
+    /// Shown above the list of rooms, with the `{count}` of rooms
+    pub rooms_header: String,
+    /// Help text of the whoami command
+    pub whoami_description: String,
+    /// Response to the whoami command, with the `{user_id}`, `{display_name}` and
+    /// `{power_level}` of the sender
+    pub whoami_response: String,
+    /// Help text of the ignore command
+    pub ignore_description: String,
+    /// Response to the ignore command, with the ignored `{user}`
+    pub ignore_done: String,
+    /// Help text of the unignore command
+    pub unignore_description: String,
+    /// Response to the unignore command, with the `{user}` that is no longer ignored
+    pub unignore_done: String,
+    /// Help text of the ignored command
+    pub ignored_description: String,
+    /// Shown above the list of ignored users
+    pub ignored_header: String,
+    /// Sent when the argument of a command isn't a valid user ID, with the given `{user}`
+    pub invalid_user: String,
+    /// Help text of the purge command
+    pub purge_description: String,
+    /// Reply to the purge command without a valid number of messages, sent as plain text
+    pub purge_usage: String,
+    /// Response to the purge command, with the `{count}` of redacted messages
+    pub purge_done: String,
+    /// Added below each page of a paginated message, with the `{page}` number and the number
+    /// of `{pages}`
+    pub page_footer: String,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Strings {
+            help_description: "Show this message".to_string(),
+            help_header: "Available commands:".to_string(),
+            not_allowed: "You don't have permission to use this command".to_string(),
+            command_timed_out: "The command timed out".to_string(),
+            busy: "I'm busy, please try again later".to_string(),
+            one_at_a_time: "Please send one command at a time".to_string(),
+            ping_description: "Check the latency and uptime of the bot".to_string(),
+            ping_response: "Pong! Latency: {latency}ms".to_string(),
+            ping_uptime: ", Uptime: {uptime}".to_string(),
+            rooms_description: "List the rooms the bot is in".to_string(),
+            rooms_header: "Joined {count} rooms:".to_string(),
+            ignore_description: "Ignore all messages from a user".to_string(),
+            ignore_done: "Ignoring {user}".to_string(),
+            unignore_description: "Stop ignoring a user".to_string(),
+            unignore_done: "No longer ignoring {user}".to_string(),
+            ignored_description: "List the ignored users".to_string(),
+            ignored_header: "Ignored users:".to_string(),
+            invalid_user: "Not a valid user ID: {user}".to_string(),
+            purge_description: "Delete the last messages the bot sent in this room".to_string(),
+            purge_usage: "Give the number of messages to delete, e.g. \"purge 10\"".to_string(),
+            purge_done: "Deleted {count} messages".to_string(),
+            page_footer: "Page {page}/{pages}".to_string(),
+            whoami_description: "Show your user ID and power level in this room".to_string(),
+            whoami_response:
+                "User ID: `{user_id}`  \nDisplay name: {display_name}  \nPower level: {power_level}"
+                    .to_string(),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Helpers for the tags the bot's account has set on rooms.
+//!
+//! Tags are private to the account, so they can be used to mark rooms for the bot without
+//! other members seeing it. Custom tags should use a namespace, e.g. "dev.headjack.enabled".
+//! Homeservers limit how many tags a room can have, so store anything larger than a few markers
+//! in account data instead.
+//!
+//! `Tags` manages the tags in one namespace, including small key-value pairs stored as
+//! "namespace.key=value". `MultiTags` does the same for several namespaces at once.
+
+use crate::{Bot, HeadjackError};
+use matrix_sdk::ruma::events::tag::{TagInfo, TagName, Tags as RoomTags};
+use matrix_sdk::Room;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+
+/// Get the names of all the tags set on the room
+/// Reads the tags from the local store, so it's cheap enough to call for every event.
+pub async fn get_tags(room: &Room) -> Result<Vec<String>, HeadjackError> {
+    let tags = room.tags().await.map_err(matrix_sdk::Error::from)?;
+    Ok(tags
+        .map(|tags| tags.keys().map(|tag| tag.to_string()).collect())
+        .unwrap_or_default())
+}
+
+/// Add a tag to the room, or update its info if it's already set
+/// Returns `TagLimitExceeded` if the homeserver won't allow more tags on the room.
+pub async fn add_tag(room: &Room, tag: TagName, info: TagInfo) -> Result<(), HeadjackError> {
+    room.set_tag(tag, info)
+        .await
+        .map_err(|e| HeadjackError::tag(room.room_id(), e))?;
+    Ok(())
+}
+
+/// The full name of a tag in a namespace
+pub(crate) fn tag_name(namespace: &str, tag: &str) -> String {
+    format!("{}.{}", namespace, tag)
+}
+
+/// The tags of a room in one namespace
+/// Changes are made locally, and applied to the room with `sync`. A tag containing an '=' is a
+/// key-value pair, split at the first '=', so plain tags can't contain an '='.
+#[derive(Debug, Clone)]
+pub struct Tags {
+    /// The room the tags are on
+    room: Room,
+    /// The namespace, e.g. "dev.headjack"
+    namespace: String,
+    /// The tags in the namespace as last synced, without the namespace
+    synced: BTreeSet<String>,
+    /// The tags in the namespace including local changes, without the namespace
+    current: BTreeSet<String>,
+}
+
+impl Tags {
+    /// Read the tags in the namespace from the room
+    pub async fn load(room: &Room, namespace: &str) -> Result<Self, HeadjackError> {
+        Ok(Tags::from_tags(room, namespace, &get_tags(room).await?))
+    }
+
+    /// Pick the tags in the namespace out of all the tags of the room
+    fn from_tags(room: &Room, namespace: &str, tags: &[String]) -> Self {
+        let prefix = format!("{}.", namespace);
+        let synced: BTreeSet<String> = tags
+            .iter()
+            .filter_map(|tag| tag.strip_prefix(&prefix))
+            .map(|tag| tag.to_string())
+            .collect();
+        Tags {
+            room: room.clone(),
+            namespace: namespace.to_string(),
+            current: synced.clone(),
+            synced,
+        }
+    }
+
+    /// Get all the tags in the namespace, without the namespace
+    /// Includes the key-value pairs as "key=value".
+    pub fn tags(&self) -> Vec<String> {
+        self.current.iter().cloned().collect()
+    }
+
+    /// Get the key-value pairs in the namespace
+    pub fn get_kvs(&self) -> HashMap<String, String> {
+        self.get_all_namespaced().0
+    }
+
+    /// Get the key-value pairs and the plain tags in the namespace
+    /// Any tag containing an '=' counts as a key-value pair, even if it was added as a plain tag.
+    pub fn get_all_namespaced(&self) -> (HashMap<String, String>, Vec<String>) {
+        let mut kvs = HashMap::new();
+        let mut raw = Vec::new();
+        for tag in &self.current {
+            match tag.split_once('=') {
+                Some((key, value)) => {
+                    kvs.insert(key.to_string(), value.to_string());
+                }
+                None => raw.push(tag.clone()),
+            }
+        }
+        (kvs, raw)
+    }
+
+    /// Get the value of a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let prefix = format!("{}=", key);
+        self.current
+            .iter()
+            .find_map(|tag| tag.strip_prefix(&prefix))
+    }
+
+    /// Set the value of a key, replacing the previous value
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.unset(key);
+        self.current.insert(format!("{}={}", key, value));
+    }
+
+    /// Remove a key and its value
+    pub fn unset(&mut self, key: &str) {
+        let prefix = format!("{}=", key);
+        self.current.retain(|tag| !tag.starts_with(&prefix));
+    }
+
+    /// Add a plain tag
+    pub fn add(&mut self, tag: &str) {
+        self.current.insert(tag.to_string());
+    }
+
+    /// Remove a plain tag
+    pub fn remove(&mut self, tag: &str) {
+        self.current.remove(tag);
+    }
+
+    /// Apply the local changes to the room
+    /// Only the tags that changed are added or removed. Returns `TagLimitExceeded` if the
+    /// homeserver won't allow more tags, the changes made until then are kept.
+    pub async fn sync(&mut self) -> Result<(), HeadjackError> {
+        self.sync_removed().await?;
+        self.sync_added().await
+    }
+
+    /// Remove the tags that were removed locally from the room
+    async fn sync_removed(&mut self) -> Result<(), HeadjackError> {
+        let removed: Vec<String> = self.synced.difference(&self.current).cloned().collect();
+        for tag in removed {
+            self.room
+                .remove_tag(tag_name(&self.namespace, &tag).into())
+                .await?;
+            self.synced.remove(&tag);
+        }
+        Ok(())
+    }
+
+    /// Add the tags that were added locally to the room
+    async fn sync_added(&mut self) -> Result<(), HeadjackError> {
+        let added: Vec<String> = self.current.difference(&self.synced).cloned().collect();
+        for tag in added {
+            let name = tag_name(&self.namespace, &tag).into();
+            add_tag(&self.room, name, TagInfo::new()).await?;
+            self.synced.insert(tag);
+        }
+        Ok(())
+    }
+}
+
+/// The tags of a room in several namespaces
+/// The tags are read from the room once for all the namespaces, and `sync` applies the changes
+/// of all of them together.
+#[derive(Debug, Clone)]
+pub struct MultiTags {
+    /// The tags of each namespace, in the order they were given
+    namespaces: Vec<Tags>,
+}
+
+impl MultiTags {
+    /// Read the tags in the namespaces from the room
+    pub async fn load(room: &Room, namespaces: &[&str]) -> Result<Self, HeadjackError> {
+        let tags = get_tags(room).await?;
+        Ok(MultiTags {
+            namespaces: namespaces
+                .iter()
+                .map(|namespace| Tags::from_tags(room, namespace, &tags))
+                .collect(),
+        })
+    }
+
+    /// Get the tags of a namespace
+    /// Returns None if the namespace wasn't loaded.
+    pub fn namespace(&self, namespace: &str) -> Option<&Tags> {
+        self.namespaces
+            .iter()
+            .find(|tags| tags.namespace == namespace)
+    }
+
+    /// Get the tags of a namespace to change them
+    /// Returns None if the namespace wasn't loaded.
+    pub fn namespace_mut(&mut self, namespace: &str) -> Option<&mut Tags> {
+        self.namespaces
+            .iter_mut()
+            .find(|tags| tags.namespace == namespace)
+    }
+
+    /// Apply the local changes of all the namespaces to the room
+    /// Removals are applied before additions, so that removing tags in one namespace makes room
+    /// for new tags in another before the homeserver's tag limit is hit.
+    pub async fn sync(&mut self) -> Result<(), HeadjackError> {
+        for tags in &mut self.namespaces {
+            tags.sync_removed().await?;
+        }
+        for tags in &mut self.namespaces {
+            tags.sync_added().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Bot {
+    /// Export all the tags of the room, including their order, e.g. for a backup
+    /// The value maps the tag names to their info, like the `tags` field of an `m.tag` event.
+    pub async fn export_tags(&self, room: &Room) -> Result<Value, HeadjackError> {
+        let tags = room.tags().await.map_err(matrix_sdk::Error::from)?;
+        Ok(serde_json::to_value(tags.unwrap_or_default())?)
+    }
+
+    /// Set the tags of an export from `export_tags` on the room
+    /// Tags the room has that aren't in the export are kept. Returns the number of tags set.
+    pub async fn import_tags(&self, room: &Room, tags: &Value) -> Result<usize, HeadjackError> {
+        let tags: RoomTags = serde_json::from_value(tags.clone())?;
+        let count = tags.len();
+        for (tag, info) in tags {
+            add_tag(room, tag, info).await?;
+        }
+        Ok(count)
+    }
+}
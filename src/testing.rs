@@ -0,0 +1,427 @@
+//! Helpers for testing bots without a real homeserver.
+//!
+//! Enabled with the `testing` feature. A `TestBot` is logged in to a fake homeserver, messages are
+//! injected as if they arrived in a sync, and the events the bot sends are captured.
+//! With the feature enabled every bot also records the messages it sends, see
+//! `Bot::recorded_messages`.
+
+use crate::{Bot, BotConfig, HeadjackError, StoreConfig};
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+use matrix_sdk::ruma::{
+    EventId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedEventId, OwnedRoomId, RoomId,
+    ServerName, UserId,
+};
+use matrix_sdk::{Client, Room, SessionMeta};
+use serde_json::{json, Value};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+/// The server name of the fake homeserver
+const SERVER_NAME: &str = "localhost";
+
+/// A bot logged in to a fake homeserver, as "@bot:localhost"
+/// Derefs to the `Bot`, so commands and handlers are registered on it as usual.
+pub struct TestBot {
+    /// The bot under test
+    bot: Bot,
+    /// The fake homeserver
+    server: MockServer,
+    /// Counter for the sync tokens
+    batch: AtomicU64,
+}
+
+/// An event the bot sent to the fake homeserver
+#[derive(Debug, Clone)]
+pub struct SentEvent {
+    /// The room the event was sent to
+    pub room_id: OwnedRoomId,
+    /// The type of the event, e.g. "m.room.message"
+    pub event_type: String,
+    /// The content of the event
+    pub content: Value,
+}
+
+/// A message the bot sent, recorded by the bot itself
+#[derive(Debug, Clone)]
+pub struct RecordedMessage {
+    /// The room the message was sent to
+    pub room_id: OwnedRoomId,
+    /// The ID of the sent event
+    pub event_id: OwnedEventId,
+    /// The type of the event, e.g. "m.room.message"
+    pub event_type: String,
+    /// The content of the event
+    pub content: Value,
+}
+
+impl RecordedMessage {
+    /// The body of the message, if it has one
+    pub fn body(&self) -> Option<&str> {
+        self.content.get("body")?.as_str()
+    }
+}
+
+impl Bot {
+    /// Get all the messages the bot has sent so far, oldest first
+    /// Includes every event sent with `Bot::send`, which the built in commands and the send
+    /// helpers use, attachments included. Only events sent directly with `Room::send` are missed.
+    pub async fn recorded_messages(&self) -> Vec<RecordedMessage> {
+        self.state.lock().await.recorded.clone()
+    }
+
+    /// Record a message the bot sent
+    pub(crate) async fn record_sent(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+        event_type: String,
+        content: Value,
+    ) {
+        self.state.lock().await.recorded.push(RecordedMessage {
+            room_id: room.room_id().to_owned(),
+            event_id: event_id.to_owned(),
+            event_type,
+            content,
+        });
+    }
+}
+
+impl Deref for TestBot {
+    type Target = Bot;
+
+    fn deref(&self) -> &Bot {
+        &self.bot
+    }
+}
+
+impl TestBot {
+    /// Create a bot logged in to a new fake homeserver
+    /// The login and store of the config are replaced, the rest is used as is.
+    pub async fn new(mut config: BotConfig) -> Result<TestBot, HeadjackError> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/versions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "versions": ["v1.8"]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path_regex(
+                r"^/_matrix/client/v3/rooms/[^/]+/(send|redact)/",
+            ))
+            .respond_with(|_: &Request| {
+                ResponseTemplate::new(200).set_body_json(json!({ "event_id": new_event_id() }))
+            })
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/_matrix/media/v3/upload"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content_uri": format!("mxc://{}/upload", SERVER_NAME)
+            })))
+            .mount(&server)
+            .await;
+        // The members come from the state events injected into the timeline
+        Mock::given(method("GET"))
+            .and(path_regex(r"^/_matrix/client/v3/rooms/[^/]+/members"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "chunk": [] })))
+            .mount(&server)
+            .await;
+        // Rooms in tests are unencrypted
+        Mock::given(method("GET"))
+            .and(path_regex(
+                r"^/_matrix/client/v3/rooms/[^/]+/state/m.room.encryption",
+            ))
+            .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                "errcode": "M_NOT_FOUND",
+                "error": "Event not found"
+            })))
+            .mount(&server)
+            .await;
+
+        config.login.homeserver_url = server.uri();
+        config.login.username = "bot".to_string();
+        config.login.password = None;
+        config.store = Some(StoreConfig::Memory);
+
+        let client = Client::builder()
+            .homeserver_url(server.uri())
+            .build()
+            .await?;
+        let session = MatrixSession {
+            meta: SessionMeta {
+                user_id: UserId::parse(format!("@bot:{}", SERVER_NAME)).expect("valid user ID"),
+                device_id: OwnedDeviceId::from("TESTDEVICE"),
+            },
+            tokens: MatrixSessionTokens {
+                access_token: "test_token".to_string(),
+                refresh_token: None,
+            },
+        };
+        client.restore_session(session).await?;
+
+        let mut bot = Bot::new(config).await;
+        bot.client = Some(client);
+        bot.watch_room_allow_lists()?;
+        Ok(TestBot {
+            bot,
+            server,
+            batch: AtomicU64::new(0),
+        })
+    }
+
+    /// Get the bot under test
+    pub fn bot(&self) -> &Bot {
+        &self.bot
+    }
+
+    /// Deliver a text message to the bot, and run the registered handlers on it
+    /// The bot is joined to the room. Returns once all the handlers have finished.
+    pub async fn inject_message(
+        &self,
+        room_id: &RoomId,
+        sender: &UserId,
+        body: &str,
+    ) -> Result<OwnedEventId, HeadjackError> {
+        let event_id = new_event_id();
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": sender,
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "content": {
+                "msgtype": "m.text",
+                "body": body
+            }
+        });
+        self.inject_event(room_id, event).await?;
+        Ok(event_id)
+    }
+
+    /// Deliver any timeline event to the bot, e.g. an edit, and run the registered handlers on it
+    /// The event is the JSON of the event as it appears in a sync, see `inject_message`.
+    pub async fn inject_event(&self, room_id: &RoomId, event: Value) -> Result<(), HeadjackError> {
+        self.inject_room(
+            room_id,
+            json!({
+                "timeline": {
+                    "events": [event],
+                    "limited": false
+                }
+            }),
+        )
+        .await
+    }
+
+    /// Deliver a change of the bot's account data in the room, e.g. a room allow_list
+    pub async fn inject_room_account_data(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        content: Value,
+    ) -> Result<(), HeadjackError> {
+        self.inject_room(
+            room_id,
+            json!({
+                "account_data": {
+                    "events": [{ "type": event_type, "content": content }]
+                }
+            }),
+        )
+        .await
+    }
+
+    /// Run a sync with the given update of a joined room
+    async fn inject_room(&self, room_id: &RoomId, update: Value) -> Result<(), HeadjackError> {
+        let batch = self.batch.fetch_add(1, Ordering::Relaxed);
+        let response = json!({
+            "next_batch": format!("batch_{}", batch + 1),
+            "rooms": {
+                "join": {
+                    room_id.as_str(): update
+                }
+            }
+        });
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/v3/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .up_to_n_times(1)
+            .mount(&self.server)
+            .await;
+        let mut settings = SyncSettings::default().timeout(Duration::ZERO);
+        if batch > 0 {
+            settings = settings.token(format!("batch_{}", batch));
+        }
+        self.bot.client()?.sync_once(settings).await?;
+        Ok(())
+    }
+
+    /// Get all the events the bot has sent so far, oldest first
+    pub async fn sent(&self) -> Vec<SentEvent> {
+        let requests = self.server.received_requests().await.unwrap_or_default();
+        requests
+            .iter()
+            .filter(|request| request.method.as_str() == "PUT")
+            .filter_map(|request| {
+                let segments: Vec<&str> = request.url.path_segments()?.collect();
+                let ["_matrix", "client", "v3", "rooms", room_id, "send", event_type, _] =
+                    segments.as_slice()
+                else {
+                    return None;
+                };
+                Some(SentEvent {
+                    room_id: RoomId::parse(percent_decode(room_id)).ok()?,
+                    event_type: percent_decode(event_type),
+                    content: serde_json::from_slice(&request.body).ok()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the bodies of all the messages the bot has sent so far, oldest first
+    pub async fn sent_bodies(&self) -> Vec<String> {
+        self.sent()
+            .await
+            .into_iter()
+            .filter(|event| event.event_type == "m.room.message")
+            .filter_map(|event| Some(event.content.get("body")?.as_str()?.to_string()))
+            .collect()
+    }
+}
+
+/// A new random event ID on the fake homeserver
+fn new_event_id() -> OwnedEventId {
+    EventId::new(<&ServerName>::try_from(SERVER_NAME).expect("valid server name"))
+}
+
+/// Decode a percent encoded path segment
+fn percent_decode(segment: &str) -> String {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| std::str::from_utf8(tail.get(..2)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+    use matrix_sdk::ruma::{room_id, user_id};
+
+    #[tokio::test]
+    async fn command_reply_is_sent() {
+        let config = BotConfig {
+            allow_list: vec!["@alice:localhost".to_string()],
+            ..Default::default()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let echo = bot.bot().clone();
+        bot.register_text_command("echo", None, None, |message, room| async move {
+            let content = RoomMessageEventContent::text_plain(message.args);
+            echo.send(&room, content).await.map_err(|_| ())?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        bot.inject_message(room_id, user_id!("@alice:localhost"), "!bot echo hello")
+            .await
+            .unwrap();
+
+        let sent = bot.sent().await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].room_id, room_id);
+        assert_eq!(sent[0].event_type, "m.room.message");
+        assert_eq!(bot.sent_bodies().await, ["hello"]);
+    }
+
+    #[tokio::test]
+    async fn commands_from_others_are_ignored() {
+        let config = BotConfig {
+            allow_list: vec!["@alice:localhost".to_string()],
+            ..Default::default()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let echo = bot.bot().clone();
+        bot.register_text_command("echo", None, None, |message, room| async move {
+            let content = RoomMessageEventContent::text_plain(message.args);
+            echo.send(&room, content).await.map_err(|_| ())?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        bot.inject_message(room_id, user_id!("@mallory:localhost"), "!bot echo hello")
+            .await
+            .unwrap();
+
+        assert!(bot.sent().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sent_messages_are_recorded() {
+        let config = BotConfig {
+            allow_list: vec!["@alice:localhost".to_string()],
+            ..Default::default()
+        };
+        let bot = TestBot::new(config).await.unwrap();
+        let responder = bot.bot().clone();
+        bot.register_text_command("twice", None, None, |message, room| async move {
+            for body in ["first", "second"] {
+                let content = RoomMessageEventContent::text_plain(body);
+                responder.send(&room, content).await.map_err(|_| ())?;
+            }
+            responder
+                .react(&room, message.event_id(), "👍")
+                .await
+                .map_err(|_| ())?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let room_id = room_id!("!room:localhost");
+        let command_id = bot
+            .inject_message(room_id, user_id!("@alice:localhost"), "!bot twice")
+            .await
+            .unwrap();
+
+        let recorded = bot.recorded_messages().await;
+        let bodies: Vec<_> = recorded.iter().map(RecordedMessage::body).collect();
+        assert_eq!(bodies, [Some("first"), Some("second"), None]);
+        assert!(recorded.iter().all(|message| message.room_id == room_id));
+        assert_eq!(recorded[2].event_type, "m.reaction");
+        assert_eq!(
+            recorded[2].content["m.relates_to"]["event_id"],
+            command_id.as_str()
+        );
+
+        // The fake homeserver saw the same events, and only the messages have bodies
+        let sent = bot.sent().await;
+        let types: Vec<_> = sent.iter().map(|event| event.event_type.as_str()).collect();
+        assert_eq!(types, ["m.room.message", "m.room.message", "m.reaction"]);
+        assert_eq!(bot.sent_bodies().await, ["first", "second"]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Device verification and cross-signing support.
+//!
+//! Other users will see the bot's device as unverified until it has been verified, and some
+//! clients refuse to send encrypted messages to unverified devices.
+//!
+//! There are two ways to deal with that here:
+//!
+//! - `Bot::enable_cross_signing_bootstrap` creates the bot's cross-signing identity, so that the
+//!   bot's devices are signed by the bot's own identity.
+//! - `Bot::accept_verification_requests` automatically accepts SAS (emoji) verification requests
+//!   from users on the allow_list.
+//!
+//! Trust tradeoffs: the bot has no human to compare the emojis, so when auto-accepting it
+//! confirms the SAS without actually comparing it. This means that the verification only proves
+//! that the other side of the verification is an allowed user as far as the homeserver is
+//! concerned, it provides no protection against a malicious homeserver performing a MITM attack.
+//! Only enable it if you trust the homeserver, and keep the allow_list tight.
+
+use crate::{is_allowed, Bot, HeadjackError};
+use futures_util::StreamExt;
+use matrix_sdk::encryption::verification::{
+    SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState,
+};
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent};
+use matrix_sdk::Client;
+use tracing::{error, info, warn};
+
+impl Bot {
+    /// Create the cross-signing identity for the bot if it doesn't have one yet
+    /// This requires the password to be set in the login config, as the homeserver requires
+    /// re-authentication to upload the cross-signing keys.
+    pub async fn enable_cross_signing_bootstrap(&self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let encryption = client.encryption();
+        if let Err(e) = encryption.bootstrap_cross_signing_if_needed(None).await {
+            let Some(response) = e.as_uiaa_response() else {
+                return Err(e.into());
+            };
+            let Some(password) = self.config().login.password.clone() else {
+                return Err(HeadjackError::Config(
+                    "a password is required to bootstrap cross-signing".to_string(),
+                ));
+            };
+            let user_id = client.user_id().ok_or(HeadjackError::NotLoggedIn)?;
+            let mut password = uiaa::Password::new(
+                uiaa::UserIdentifier::UserIdOrLocalpart(user_id.localpart().to_owned()),
+                password,
+            );
+            password.session = response.session.clone();
+            encryption
+                .bootstrap_cross_signing(Some(uiaa::AuthData::Password(password)))
+                .await?;
+        }
+        info!("Cross-signing is set up");
+        Ok(())
+    }
+
+    /// Adds a callback to automatically accept verification requests
+    /// Only accepts requests from users on the allow_list.
+    /// The SAS is confirmed without comparison, see the module docs for the tradeoffs.
+    pub fn accept_verification_requests(&self) -> Result<(), HeadjackError> {
+        let client = self.client()?;
+        let username = self.full_name()?;
+        {
+            let bot = self.clone();
+            let username = username.clone();
+            client.add_event_handler(
+                move |event: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
+                    let allow_list = bot.allow_list_regex();
+                    if !is_allowed(&allow_list, event.sender.as_str(), &username) {
+                        warn!("Ignoring verification request from {}", event.sender);
+                        return;
+                    }
+                    let Some(request) = client
+                        .encryption()
+                        .get_verification_request(&event.sender, &event.content.transaction_id)
+                        .await
+                    else {
+                        return;
+                    };
+                    tokio::spawn(handle_verification_request(request));
+                },
+            );
+        }
+        let bot = self.clone();
+        client.add_event_handler(
+            move |event: OriginalSyncRoomMessageEvent, client: Client| async move {
+                let MessageType::VerificationRequest(_) = &event.content.msgtype else {
+                    return;
+                };
+                let allow_list = bot.allow_list_regex();
+                if !is_allowed(&allow_list, event.sender.as_str(), &username) {
+                    warn!("Ignoring verification request from {}", event.sender);
+                    return;
+                }
+                let Some(request) = client
+                    .encryption()
+                    .get_verification_request(&event.sender, &event.event_id)
+                    .await
+                else {
+                    return;
+                };
+                tokio::spawn(handle_verification_request(request));
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Accept a verification request and follow it through to the SAS verification
+async fn handle_verification_request(request: VerificationRequest) {
+    info!(
+        "Accepting verification request from {}",
+        request.other_user_id()
+    );
+    if let Err(e) = request.accept().await {
+        error!("Error accepting verification request: {:?}", e);
+        return;
+    }
+    let mut changes = request.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned {
+                verification: Verification::SasV1(sas),
+            } => {
+                handle_sas(sas).await;
+                break;
+            }
+            VerificationRequestState::Transitioned { .. } => {
+                warn!("Unsupported verification method, only SAS is supported");
+                break;
+            }
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+            _ => (),
+        }
+    }
+}
+
+/// Accept and confirm a SAS verification
+async fn handle_sas(sas: SasVerification) {
+    if let Err(e) = sas.accept().await {
+        error!("Error accepting SAS verification: {:?}", e);
+        return;
+    }
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { .. } => {
+                // There is nobody to compare the emojis, so we confirm them blindly
+                if let Err(e) = sas.confirm().await {
+                    error!("Error confirming SAS verification: {:?}", e);
+                    break;
+                }
+            }
+            SasState::Done { .. } => {
+                info!(
+                    "Successfully verified device {} of {}",
+                    sas.other_device().device_id(),
+                    sas.other_user_id()
+                );
+                break;
+            }
+            SasState::Cancelled(info) => {
+                warn!("SAS verification cancelled: {}", info.reason());
+                break;
+            }
+            _ => (),
+        }
+    }
+}